@@ -0,0 +1,531 @@
+//! Async NubDB client built on tokio
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures_core::Stream;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use super::{
+    duration_to_secs, escape_value, parse_integer, parse_set_nx, parse_size, parse_value,
+    reply_error, scan_page, server_error, set_command, Config, NubError, QuoteStyle, Response, ScanOptions,
+};
+
+/// Async counterpart of [`NubDB`](super::NubDB)
+///
+/// Method names and semantics match the blocking client.
+///
+/// # Cancellation
+///
+/// Dropping a command future after its request was written but before the
+/// reply was read would leave that reply on the socket for the next command
+/// to pick up. To avoid returning another command's reply, the connection
+/// tracks whether a command is in flight; if a future was dropped mid-command
+/// every later call fails with `NubError::Desynchronized` and the client must
+/// be reconnected.
+pub struct AsyncNubDB {
+    stream: BufReader<TcpStream>,
+    in_flight: bool,
+    /// The reply and key and value size limits; nothing else applies
+    config: Config,
+    /// Bytes the reply being read may still take up
+    remaining: usize,
+}
+
+impl AsyncNubDB {
+    /// Connect to NubDB server
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self, NubError> {
+        let stream = TcpStream::connect(addr).await?;
+
+        Ok(AsyncNubDB {
+            stream: BufReader::new(stream),
+            in_flight: false,
+            config: Config::default(),
+            remaining: 0,
+        })
+    }
+
+    /// Fail a reply larger than `limit` bytes with `NubError::ResponseTooLarge`
+    ///
+    /// As with
+    /// [`NubDBBuilder::max_response_bytes`](super::NubDBBuilder::max_response_bytes),
+    /// the limit counts one whole reply as sent and is checked as the reply
+    /// is read, so a huge reply is never held in memory. The rest of it is
+    /// left unread, so every later command fails with
+    /// `NubError::Desynchronized`. Defaults to
+    /// [`NubDBBuilder::DEFAULT_MAX_RESPONSE_BYTES`](super::NubDBBuilder::DEFAULT_MAX_RESPONSE_BYTES).
+    pub fn set_max_response_bytes(&mut self, limit: usize) {
+        self.config.max_response_bytes = limit;
+    }
+
+    /// Fail a command on a key longer than `limit` bytes with
+    /// `NubError::KeyTooLong`, before anything is sent, as
+    /// [`NubDBBuilder::max_key_length`](super::NubDBBuilder::max_key_length)
+    /// does. Defaults to
+    /// [`NubDBBuilder::DEFAULT_MAX_KEY_LENGTH`](super::NubDBBuilder::DEFAULT_MAX_KEY_LENGTH).
+    pub fn set_max_key_length(&mut self, limit: usize) {
+        self.config.max_key_length = limit;
+    }
+
+    /// Fail a write of a value longer than `limit` bytes with
+    /// `NubError::ValueTooLong`, before anything is sent, as
+    /// [`NubDBBuilder::max_value_length`](super::NubDBBuilder::max_value_length)
+    /// does. Defaults to
+    /// [`NubDBBuilder::DEFAULT_MAX_VALUE_LENGTH`](super::NubDBBuilder::DEFAULT_MAX_VALUE_LENGTH).
+    pub fn set_max_value_length(&mut self, limit: usize) {
+        self.config.max_value_length = limit;
+    }
+
+    /// Connect to NubDB server, giving up after `timeout`
+    pub async fn connect_timeout(addr: impl ToSocketAddrs, timeout: Duration) -> Result<Self, NubError> {
+        match tokio::time::timeout(timeout, Self::connect(addr)).await {
+            Ok(client) => client,
+            Err(_) => Err(NubError::Timeout),
+        }
+    }
+
+    /// Send a command and get response
+    ///
+    /// Error replies (`ERR ...`, `-ERR ...`) are returned as `NubError::Protocol`.
+    async fn send_command(&mut self, cmd: &str) -> Result<String, NubError> {
+        self.write_command(cmd).await?;
+
+        let response = self.read_line().await?;
+        self.in_flight = false;
+        check_reply(response)
+    }
+
+    /// Send a command whose reply is an array: a `*<count>` header line
+    /// followed by `count` reply lines
+    async fn send_array_command(&mut self, cmd: &str) -> Result<Vec<String>, NubError> {
+        self.write_command(cmd).await?;
+
+        let header = self.read_line().await?;
+        let count = match header.strip_prefix('*').and_then(|n| n.parse::<usize>().ok()) {
+            Some(count) => count,
+            None => {
+                // A non-array reply is still a complete reply
+                self.in_flight = false;
+                check_reply(header.clone())?;
+                return Err(NubError::UnexpectedResponse(header));
+            }
+        };
+
+        // The count is the server's word, so let the items prove it
+        let mut items = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            items.push(self.read_line().await?);
+        }
+        self.in_flight = false;
+        items.into_iter().map(check_reply).collect()
+    }
+
+    /// Write one command line, marking the connection as busy until its
+    /// reply has been read in full
+    async fn write_command(&mut self, cmd: &str) -> Result<(), NubError> {
+        if self.in_flight {
+            return Err(NubError::Desynchronized);
+        }
+
+        self.in_flight = true;
+        self.remaining = self.config.max_response_bytes;
+        let stream = self.stream.get_mut();
+        stream.write_all(cmd.as_bytes()).await?;
+        stream.write_all(b"\n").await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Read a single trimmed reply line as-is, within what is left of the
+    /// reply's size limit
+    async fn read_line(&mut self) -> Result<String, NubError> {
+        let mut line = Vec::new();
+        let read = (&mut self.stream).take(self.remaining as u64).read_until(b'\n', &mut line).await?;
+        if read == 0 && self.remaining > 0 {
+            return Err(NubError::ConnectionClosed);
+        }
+        self.remaining -= read;
+        if !line.ends_with(b"\n") && self.remaining == 0 {
+            return Err(NubError::ResponseTooLarge(self.config.max_response_bytes));
+        }
+        let line = String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(line.trim().to_string())
+    }
+
+    /// SET key-value pair
+    pub async fn set(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.config.check_key(key)?;
+        self.config.check_value(value.len())?;
+        let response = self.send_command(&set_command(key, value, ttl, QuoteStyle::Double)?).await?;
+        Ok(response == "OK")
+    }
+
+    /// SET key-value pair with the TTL given as a `Duration`
+    ///
+    /// The TTL is truncated to whole seconds; durations shorter than one
+    /// second are rejected with `NubError::InvalidArgument`.
+    pub async fn set_with_duration(&mut self, key: &str, value: &str, ttl: Option<Duration>) -> Result<bool, NubError> {
+        let ttl = ttl.map(duration_to_secs).transpose()?;
+        self.set(key, value, ttl).await
+    }
+
+    /// SETNX set a key only if it does not exist yet
+    ///
+    /// Returns `true` if the key was created and `false` if it already
+    /// existed, in which case its value is left untouched. The optional TTL
+    /// lets a lock taken this way expire on its own.
+    pub async fn set_nx(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.config.check_key(key)?;
+        self.config.check_value(value.len())?;
+        let cmd = match ttl {
+            Some(t) => format!(r#"SETNX {} "{}" {}"#, key, escape_value(value), t),
+            None => format!(r#"SETNX {} "{}""#, key, escape_value(value)),
+        };
+
+        let response = self.send_command(&cmd).await?;
+        parse_set_nx(Response::parse(&response))
+    }
+
+    /// MSET several key-value pairs in one round trip
+    ///
+    /// An empty `pairs` slice is a no-op. Any reply other than `OK` is an error.
+    pub async fn mset(&mut self, pairs: &[(&str, &str)]) -> Result<bool, NubError> {
+        for (key, value) in pairs {
+            self.config.check_key(key)?;
+            self.config.check_value(value.len())?;
+        }
+        if pairs.is_empty() {
+            return Ok(true);
+        }
+
+        let mut cmd = String::from("MSET");
+        for (key, value) in pairs {
+            cmd.push_str(&format!(r#" {} "{}""#, key, escape_value(value)));
+        }
+
+        let response = self.send_command(&cmd).await?;
+        if response == "OK" {
+            Ok(true)
+        } else {
+            Err(NubError::UnexpectedResponse(response))
+        }
+    }
+
+    /// GET value by key
+    pub async fn get(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        self.config.check_key(key)?;
+        let response = self.send_command(&format!("GET {}", key)).await?;
+        Ok(parse_value(&response))
+    }
+
+    /// GETSET atomically replace a value, returning the previous one
+    ///
+    /// Returns `None` if the key did not exist before.
+    pub async fn get_set(&mut self, key: &str, value: &str) -> Result<Option<String>, NubError> {
+        self.config.check_key(key)?;
+        self.config.check_value(value.len())?;
+        let cmd = format!(r#"GETSET {} "{}""#, key, escape_value(value));
+        let response = self.send_command(&cmd).await?;
+        Ok(parse_value(&response))
+    }
+
+    /// MGET values for several keys in one round trip
+    ///
+    /// The result is aligned with `keys`, with `None` for missing keys.
+    pub async fn mget(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>, NubError> {
+        self.config.check_keys(keys)?;
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let replies = self.send_array_command(&format!("MGET {}", keys.join(" "))).await?;
+        if replies.len() != keys.len() {
+            return Err(NubError::UnexpectedResponse(format!(
+                "MGET returned {} values for {} keys", replies.len(), keys.len()
+            )));
+        }
+
+        Ok(replies.iter().map(|r| parse_value(r)).collect())
+    }
+
+    /// DELETE key
+    pub async fn delete(&mut self, key: &str) -> Result<bool, NubError> {
+        self.config.check_key(key)?;
+        let response = self.send_command(&format!("DELETE {}", key)).await?;
+        Ok(response == "OK")
+    }
+
+    /// EXISTS check if key exists
+    pub async fn exists(&mut self, key: &str) -> Result<bool, NubError> {
+        self.config.check_key(key)?;
+        let response = self.send_command(&format!("EXISTS {}", key)).await?;
+        Ok(response == "1")
+    }
+
+    /// INCR increment counter
+    pub async fn incr(&mut self, key: &str) -> Result<i64, NubError> {
+        self.incr_by(key, 1).await
+    }
+
+    /// DECR decrement counter
+    pub async fn decr(&mut self, key: &str) -> Result<i64, NubError> {
+        self.decr_by(key, 1).await
+    }
+
+    /// INCRBY increment counter by `amount`, which may be negative
+    pub async fn incr_by(&mut self, key: &str, amount: i64) -> Result<i64, NubError> {
+        self.config.check_key(key)?;
+        let response = self.send_command(&format!("INCRBY {} {}", key, amount)).await?;
+        parse_integer(&response)
+    }
+
+    /// DECRBY decrement counter by `amount`, which may be negative
+    pub async fn decr_by(&mut self, key: &str, amount: i64) -> Result<i64, NubError> {
+        self.config.check_key(key)?;
+        let response = self.send_command(&format!("DECRBY {} {}", key, amount)).await?;
+        parse_integer(&response)
+    }
+
+    /// EXPIRE set a key's time to live in seconds
+    ///
+    /// Returns `false` if the key does not exist.
+    pub async fn expire(&mut self, key: &str, seconds: u32) -> Result<bool, NubError> {
+        self.config.check_key(key)?;
+        let response = self.send_command(&format!("EXPIRE {} {}", key, seconds)).await?;
+        Ok(parse_integer(&response)? == 1)
+    }
+
+    /// TTL get the remaining time to live of a key in seconds
+    ///
+    /// See [`NubDB::ttl`](super::NubDB::ttl) for the sentinel handling.
+    pub async fn ttl(&mut self, key: &str) -> Result<Option<i64>, NubError> {
+        self.config.check_key(key)?;
+        let response = self.send_command(&format!("TTL {}", key)).await?;
+        match parse_integer(&response)? {
+            -2 => Err(NubError::KeyNotFound(key.to_string())),
+            -1 => Ok(None),
+            seconds => Ok(Some(seconds)),
+        }
+    }
+
+    /// TTL as a `Duration`
+    pub async fn ttl_duration(&mut self, key: &str) -> Result<Option<Duration>, NubError> {
+        let ttl = self.ttl(key).await?;
+        Ok(ttl.map(|secs| Duration::from_secs(secs.max(0) as u64)))
+    }
+
+    /// PING check the connection, returning the round-trip time
+    pub async fn ping(&mut self) -> Result<Duration, NubError> {
+        let start = Instant::now();
+        let response = self.send_command("PING").await?;
+        let elapsed = start.elapsed();
+
+        if response != "PONG" {
+            return Err(NubError::UnexpectedResponse(response));
+        }
+        Ok(elapsed)
+    }
+
+    /// Whether a command future was dropped mid-command, see the type docs
+    #[cfg(feature = "deadpool")]
+    pub(crate) fn is_desynchronized(&self) -> bool {
+        self.in_flight
+    }
+
+    /// SIZE get number of keys
+    pub async fn size(&mut self) -> Result<usize, NubError> {
+        let response = self.send_command("SIZE").await?;
+        parse_size(&response)
+    }
+
+    /// SCAN the keyspace a page at a time, as a [`Stream`] of keys
+    ///
+    /// Async counterpart of [`NubDB::scan`](super::NubDB::scan), taking the
+    /// same options and giving the same guarantees. A page is only fetched
+    /// once the keys of the one before have all been taken, so a consumer
+    /// that stops early, for example with `take`, sends no more SCANs.
+    ///
+    /// ```no_run
+    /// # async fn run() -> Result<(), nubdb::NubError> {
+    /// use futures::StreamExt;
+    ///
+    /// let mut client = nubdb::AsyncNubDB::connect("localhost:6379").await?;
+    /// let mut sessions = client.scan(Some("session:*")).take(100);
+    /// while let Some(key) = sessions.next().await {
+    ///     println!("{}", key?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// Dropping the stream while a page is being fetched cancels that SCAN,
+    /// with the same effect as dropping any other command future.
+    pub fn scan(&mut self, options: impl Into<ScanOptions>) -> AsyncScan<'_> {
+        AsyncScan {
+            options: options.into(),
+            page: Vec::new().into_iter(),
+            state: ScanState::Idle(self, 0),
+        }
+    }
+
+    /// CLEAR delete all keys
+    pub async fn clear(&mut self) -> Result<bool, NubError> {
+        let response = self.send_command("CLEAR").await?;
+        Ok(response == "OK")
+    }
+
+    /// Close connection
+    pub async fn close(&mut self) -> Result<(), NubError> {
+        self.send_command("QUIT").await?;
+        Ok(())
+    }
+}
+
+/// Keys streamed by [`AsyncNubDB::scan`]
+pub struct AsyncScan<'a> {
+    options: ScanOptions,
+    page: std::vec::IntoIter<String>,
+    state: ScanState<'a>,
+}
+
+/// A SCAN in progress, handing the client back with the next cursor and page
+type PageFuture<'a> = Pin<Box<dyn Future<Output = (&'a mut AsyncNubDB, Result<(u64, Vec<String>), NubError>)> + Send + 'a>>;
+
+enum ScanState<'a> {
+    /// Waiting to fetch the page at the cursor
+    Idle(&'a mut AsyncNubDB, u64),
+    Fetching(PageFuture<'a>),
+    /// The cursor came back to `0`, or a page failed
+    Finished,
+}
+
+impl Stream for AsyncScan<'_> {
+    type Item = Result<String, NubError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(key) = this.page.next() {
+                return Poll::Ready(Some(Ok(key)));
+            }
+            match std::mem::replace(&mut this.state, ScanState::Finished) {
+                ScanState::Finished => return Poll::Ready(None),
+                ScanState::Idle(client, cursor) => {
+                    let cmd = this.options.command(cursor);
+                    this.state = ScanState::Fetching(Box::pin(async move {
+                        let page = match client.send_array_command(&cmd).await {
+                            Ok(reply) => scan_page(reply.iter().filter_map(|item| parse_value(item)).collect()),
+                            Err(e) => Err(e),
+                        };
+                        (client, page)
+                    }));
+                }
+                ScanState::Fetching(mut fetch) => match fetch.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        this.state = ScanState::Fetching(fetch);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready((client, Ok((cursor, keys)))) => {
+                        this.page = keys.into_iter();
+                        if cursor != 0 {
+                            this.state = ScanState::Idle(client, cursor);
+                        }
+                    }
+                    Poll::Ready((_, Err(e))) => return Poll::Ready(Some(Err(e))),
+                },
+            }
+        }
+    }
+}
+
+/// Map error replies to `NubError::Protocol` or `NubError::WrongType`
+fn check_reply(response: String) -> Result<String, NubError> {
+    match server_error(&response) {
+        Some(msg) => Err(reply_error(msg)),
+        None => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs a running server"]
+    async fn test_basic_operations() {
+        let mut client = AsyncNubDB::connect("localhost:6379").await.unwrap();
+
+        assert!(client.set("async:name", "Alice", None).await.unwrap());
+        assert_eq!(client.get("async:name").await.unwrap(), Some("Alice".to_string()));
+        assert!(client.exists("async:name").await.unwrap());
+        assert!(client.delete("async:name").await.unwrap());
+        assert!(!client.exists("async:name").await.unwrap());
+
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a running server"]
+    async fn test_scan_stream() {
+        let mut client = AsyncNubDB::connect("localhost:6379").await.unwrap();
+        for i in 0..25 {
+            client.set(&format!("asyncscan:{}", i), "v", None).await.unwrap();
+        }
+
+        let options = ScanOptions { pattern: Some("asyncscan:*".to_string()), count: Some(4), ..ScanOptions::default() };
+        let mut scan = client.scan(options);
+        let mut keys = Vec::new();
+        while let Some(key) = std::future::poll_fn(|cx| Pin::new(&mut scan).poll_next(cx)).await {
+            keys.push(key.unwrap());
+        }
+        keys.sort();
+        let mut expected: Vec<String> = (0..25).map(|i| format!("asyncscan:{}", i)).collect();
+        expected.sort();
+        assert_eq!(keys, expected);
+
+        // Finished for good, and the client is usable again
+        assert!(std::future::poll_fn(|cx| Pin::new(&mut scan).poll_next(cx)).await.is_none());
+        drop(scan);
+        assert!(client.exists("asyncscan:0").await.unwrap());
+        client.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_response_bytes() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0; 64];
+            let _ = tokio::io::AsyncReadExt::read(&mut socket, &mut request).await;
+            // A reply line that never ends, until the client hangs up
+            let chunk = vec![b'x'; 64 * 1024];
+            while socket.write_all(&chunk).await.is_ok() {}
+        });
+
+        let mut client = AsyncNubDB::connect(addr).await.unwrap();
+        client.set_max_response_bytes(1 << 20);
+        assert!(matches!(client.get("endless").await, Err(NubError::ResponseTooLarge(1048576))));
+        assert!(matches!(client.get("key").await, Err(NubError::Desynchronized)));
+    }
+
+    #[tokio::test]
+    async fn test_key_and_value_limits() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = AsyncNubDB::connect(listener.local_addr().unwrap()).await.unwrap();
+        let long_key = "k".repeat(4097);
+        assert!(matches!(client.get(&long_key).await, Err(NubError::KeyTooLong(4097, 4096))));
+
+        // Nothing is sent, so a server that never answers is never waited on
+        client.set_max_key_length(4);
+        client.set_max_value_length(2);
+        assert!(matches!(client.delete("12345").await, Err(NubError::KeyTooLong(5, 4))));
+        assert!(matches!(client.mget(&["a", "12345"]).await, Err(NubError::KeyTooLong(5, 4))));
+        assert!(matches!(client.set("k", "123", None).await, Err(NubError::ValueTooLong(3, 2))));
+        assert!(matches!(client.mset(&[("k", "123")]).await, Err(NubError::ValueTooLong(3, 2))));
+    }
+}
@@ -0,0 +1,186 @@
+//! Hooks for keeping an audit trail of writes
+
+use std::error::Error;
+
+use super::NubError;
+
+/// The commands reported to the audit hook: everything that can change
+/// what is stored
+const WRITE_COMMANDS: &[&str] = &[
+    "APPEND", "BLPOP", "CLEAR", "COPY", "DECR", "DECRBY", "DEL", "DELETE", "EXPIRE", "EXPIREAT",
+    "FLUSHALL", "FLUSHDB", "GETDEL", "GETSET", "HDEL", "HINCRBY", "HSET", "INCR", "INCRBY",
+    "INCRBYFLOAT", "LPOP", "LPUSH", "MSET", "PERSIST", "PEXPIRE", "PSETEX", "RENAME", "RESTORE",
+    "RPOP", "RPUSH", "SADD", "SET", "SETBIT", "SETBYTES", "SETEX", "SETNX", "SETRANGE", "SREM",
+    "UNLINK", "ZADD", "ZPOPMAX", "ZPOPMIN", "ZREM",
+];
+
+/// The function set with [`NubDBBuilder::audit`]
+///
+/// [`NubDBBuilder::audit`]: super::NubDBBuilder::audit
+pub type AuditHook = dyn Fn(&AuditEvent<'_>) -> Result<(), Box<dyn Error + Send + Sync>> + Send + Sync;
+
+/// One write reported to the audit hook, see [`NubDBBuilder::audit`]
+///
+/// Each write is reported twice: `Before` it is sent and `After` its reply
+/// arrives. Only the command name and the keys it names are passed; values,
+/// scores and other arguments never are.
+///
+/// ```
+/// # use nubdb::{AuditEvent, NubDB};
+/// let builder = NubDB::builder().audit(|event| {
+///     if let AuditEvent::After { command, keys, result } = event {
+///         eprintln!("{} {:?}: {}", command, keys, if result.is_ok() { "ok" } else { "failed" });
+///     }
+///     Ok(())
+/// });
+/// ```
+///
+/// [`NubDBBuilder::audit`]: super::NubDBBuilder::audit
+#[derive(Debug, Clone, Copy)]
+pub enum AuditEvent<'a> {
+    /// The command is about to be sent. Failing here aborts it, so nothing
+    /// reaches the server.
+    Before { command: &'a str, keys: &'a [&'a str] },
+    /// The command was sent and `result` is how it went. An error reply
+    /// from the server is passed as the error the caller gets.
+    After { command: &'a str, keys: &'a [&'a str], result: Result<(), &'a NubError> },
+}
+
+impl AuditEvent<'_> {
+    /// The command name, such as `SET`
+    pub fn command(&self) -> &str {
+        match self {
+            AuditEvent::Before { command, .. } | AuditEvent::After { command, .. } => command,
+        }
+    }
+
+    /// The keys the command writes to, empty for commands such as `FLUSHDB`
+    /// that name none
+    pub fn keys(&self) -> &[&str] {
+        match self {
+            AuditEvent::Before { keys, .. } | AuditEvent::After { keys, .. } => keys,
+        }
+    }
+}
+
+/// The name and keys of `cmd`, or `None` if it is not a write
+pub(crate) fn write_keys(cmd: &str) -> Option<(&str, Vec<&str>)> {
+    let mut args = Args(cmd);
+    let name = args.next()?;
+    if !WRITE_COMMANDS.iter().any(|write| write.eq_ignore_ascii_case(name)) {
+        return None;
+    }
+
+    let args: Vec<&str> = args.collect();
+    let keys = match name.to_ascii_uppercase().as_str() {
+        "CLEAR" | "FLUSHALL" | "FLUSHDB" => Vec::new(),
+        "DEL" | "UNLINK" => args,
+        "MSET" => args.into_iter().step_by(2).collect(),
+        "COPY" | "RENAME" => args.into_iter().take(2).collect(),
+        // The last argument is the timeout
+        "BLPOP" => args.split_last().map_or(Vec::new(), |(_, keys)| keys.to_vec()),
+        _ => args.into_iter().take(1).collect(),
+    };
+    Some((name, keys))
+}
+
+/// The arguments of a command line, with quoted arguments kept whole and
+/// their outer quotes removed
+struct Args<'a>(&'a str);
+
+impl<'a> Iterator for Args<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        let rest = self.0.trim_start_matches(' ');
+        let quote = rest.chars().next().filter(|c| matches!(c, '"' | '\''));
+        let end = match quote {
+            Some(quote) => {
+                let mut escaped = false;
+                rest.char_indices().skip(1)
+                    .find(|&(_, c)| {
+                        let closes = c == quote && !escaped;
+                        escaped = c == '\\' && !escaped;
+                        closes
+                    })
+                    .map_or(rest.len(), |(i, _)| i + 1)
+            }
+            None => rest.find(' ').unwrap_or(rest.len()),
+        };
+        if end == 0 {
+            return None;
+        }
+
+        self.0 = &rest[end..];
+        let arg = &rest[..end];
+        Some(match quote {
+            Some(quote) if arg.len() >= 2 && arg.ends_with(quote) => &arg[1..arg.len() - 1],
+            _ => arg,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::tests::mock_node;
+    use crate::NubDB;
+
+    #[test]
+    fn test_write_keys() {
+        assert_eq!(write_keys(r#"SET k "a b" 5"#), Some(("SET", vec!["k"])));
+        assert_eq!(write_keys(r#"MSET a "1 \" 2" b '3'"#), Some(("MSET", vec!["a", "b"])));
+        assert_eq!(write_keys(r#"HSET "user:1" "name" "Ada""#), Some(("HSET", vec!["user:1"])));
+        assert_eq!(write_keys("COPY a b REPLACE"), Some(("COPY", vec!["a", "b"])));
+        assert_eq!(write_keys("BLPOP q 5"), Some(("BLPOP", vec!["q"])));
+        assert_eq!(write_keys("DEL a b c"), Some(("DEL", vec!["a", "b", "c"])));
+        assert_eq!(write_keys("FLUSHDB"), Some(("FLUSHDB", vec![])));
+        assert_eq!(write_keys("GET k"), None);
+    }
+
+    #[test]
+    fn test_audit_hook() {
+        let (addr, seen) = mock_node(|cmd| match cmd {
+            "GET k" => r#""v""#.to_string(),
+            c if c.starts_with("INCR") => "ERR value is not an integer".to_string(),
+            _ => "OK".to_string(),
+        });
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let mut client = NubDB::builder()
+            .audit(move |event| {
+                let outcome = match event {
+                    AuditEvent::Before { .. } => "before",
+                    AuditEvent::After { result: Ok(()), .. } => "ok",
+                    AuditEvent::After { result: Err(_), .. } => "failed",
+                };
+                recorded.lock().unwrap().push(format!("{} {} {}", outcome, event.command(), event.keys().join(",")));
+                if event.keys().contains(&"denied") {
+                    return Err("audit log unavailable".into());
+                }
+                Ok(())
+            })
+            .connect(&addr)
+            .unwrap();
+
+        assert!(client.set("k", "secret value", None).unwrap());
+        client.get("k").unwrap();
+        assert!(client.incr("k").is_err());
+        assert!(matches!(client.delete("denied"), Err(NubError::AuditFailed(_))));
+        let replies = client.pipeline().get("k").incr("n").del("k").execute().unwrap();
+        assert_eq!(replies.len(), 3);
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            "before SET k", "ok SET k",
+            "before INCRBY k", "failed INCRBY k",
+            "before DELETE denied",
+            "before INCR n", "before DELETE k", "failed INCR n", "ok DELETE k",
+        ]);
+        // The failed audit kept the DELETE from being sent
+        assert_eq!(*seen.lock().unwrap(), vec![
+            r#"SET k "secret value""#, "GET k", "INCRBY k 1", "GET k", "INCR n", "DELETE k",
+        ]);
+    }
+}
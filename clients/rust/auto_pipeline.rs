@@ -0,0 +1,289 @@
+//! Pipelines that flush themselves as they fill up
+
+use super::{set_command, NubDB, NubError, Response};
+
+/// A pipeline that sends its queue whenever it reaches a size limit
+///
+/// Created by [`NubDB::auto_pipeline`]. Commands are queued like with
+/// [`Pipeline`](super::Pipeline), but once `max_commands` commands or
+/// `max_bytes` bytes of command text are queued, the queue is written in one
+/// go and every reply is read before the queueing method returns.
+/// [`AutoPipeline::flush`] sends whatever is left, and so does dropping the
+/// pipeline, ignoring any error; call [`AutoPipeline::finish`] to see it.
+///
+/// Replies come back in the order the commands were queued, error replies
+/// included as `Response::Error`. By default they are collected for
+/// [`AutoPipeline::finish`] or [`AutoPipeline::take_responses`]; with
+/// [`AutoPipeline::on_response`] each is handed to a callback instead, so
+/// memory use stays flat during a long load.
+///
+/// Only one batch is ever in flight and a flush waits for all of its
+/// replies, so a slow server slows down the code queueing commands rather
+/// than letting the queue grow: at most `max_commands` commands, or a little
+/// over `max_bytes` bytes, are buffered at any time, so the limits are a
+/// hard cap on the queue as well as the batch size.
+/// [`AutoPipeline::queued_len`] and [`AutoPipeline::inflight_len`] report
+/// where the pipeline stands, and are passed to the client's
+/// [`CommandObserver::on_pipeline`] as they change.
+///
+/// ```no_run
+/// # use nubdb::{NubDB, NubError, Response};
+/// # fn main() -> Result<(), NubError> {
+/// let mut client = NubDB::connect("localhost:6379")?;
+/// let mut failed = 0;
+/// let mut pipeline = client.auto_pipeline(1000, 1 << 20)
+///     .on_response(|response| if matches!(response, Response::Error(_)) { failed += 1 });
+/// for i in 0..100_000 {
+///     pipeline.set(&format!("item:{}", i), "value", None)?;
+/// }
+/// pipeline.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`CommandObserver::on_pipeline`]: super::CommandObserver::on_pipeline
+pub struct AutoPipeline<'a> {
+    client: &'a mut NubDB,
+    commands: Vec<String>,
+    bytes: usize,
+    max_commands: usize,
+    max_bytes: usize,
+    /// Commands in the last batch sent whose replies were not read
+    in_flight: usize,
+    responses: Vec<Response>,
+    on_response: Option<Box<dyn FnMut(Response) + 'a>>,
+}
+
+impl NubDB {
+    /// Start a pipeline that flushes itself after `max_commands` commands or
+    /// `max_bytes` bytes of queued command text, whichever comes first
+    pub fn auto_pipeline(&mut self, max_commands: usize, max_bytes: usize) -> AutoPipeline<'_> {
+        AutoPipeline {
+            client: self,
+            commands: Vec::new(),
+            bytes: 0,
+            max_commands: max_commands.max(1),
+            max_bytes: max_bytes.max(1),
+            in_flight: 0,
+            responses: Vec::new(),
+            on_response: None,
+        }
+    }
+}
+
+impl<'a> AutoPipeline<'a> {
+    /// Hand each reply to `callback` as it arrives instead of collecting it
+    pub fn on_response(mut self, callback: impl FnMut(Response) + 'a) -> Self {
+        self.on_response = Some(Box::new(callback));
+        self
+    }
+
+    /// Queue a SET
+    pub fn set(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<&mut Self, NubError> {
+        self.client.config.check_value(value.len())?;
+        let cmd = set_command(key, value, ttl, self.client.config.quote_style)?;
+        self.push(key, cmd)
+    }
+
+    /// Queue a GET
+    pub fn get(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.push(key, format!("GET {}", key))
+    }
+
+    /// Queue a DELETE
+    pub fn del(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.push(key, format!("DELETE {}", key))
+    }
+
+    /// Queue an EXISTS
+    pub fn exists(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.push(key, format!("EXISTS {}", key))
+    }
+
+    /// Queue an INCR
+    pub fn incr(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.push(key, format!("INCR {}", key))
+    }
+
+    /// Queue a DECR
+    pub fn decr(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.push(key, format!("DECR {}", key))
+    }
+
+    /// Queue `cmd`, flushing if that reaches a limit
+    fn push(&mut self, key: &str, cmd: String) -> Result<&mut Self, NubError> {
+        self.client.config.check_key(key)?;
+        self.bytes += cmd.len() + 1;
+        self.commands.push(cmd);
+        if self.commands.len() >= self.max_commands || self.bytes >= self.max_bytes {
+            self.flush()?;
+        } else {
+            self.observe();
+        }
+        Ok(self)
+    }
+
+    /// Number of commands queued and not yet sent
+    pub fn queued_len(&self) -> usize {
+        self.commands.len()
+    }
+
+    /// Number of commands sent whose replies have not been read
+    ///
+    /// A flush waits for every reply, so this is zero unless the last flush
+    /// failed part way, in which case the server may or may not have run
+    /// the commands it counts. It is zero again after the next flush.
+    pub fn inflight_len(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Same as [`AutoPipeline::queued_len`]
+    #[deprecated(note = "use queued_len")]
+    pub fn pending(&self) -> usize {
+        self.queued_len()
+    }
+
+    /// Report the queue and in-flight batch to the observer, if any
+    fn observe(&self) {
+        if let Some(observer) = &self.client.config.observer {
+            observer.on_pipeline(self.commands.len(), self.in_flight);
+        }
+    }
+
+    /// Send every queued command and wait for the replies
+    pub fn flush(&mut self) -> Result<(), NubError> {
+        if self.commands.is_empty() {
+            return Ok(());
+        }
+
+        let commands = std::mem::take(&mut self.commands);
+        self.bytes = 0;
+        self.in_flight = commands.len();
+        self.observe();
+        let sent = self.client.send_batch(&commands);
+        // An audit hook fails before the batch is sent or after its replies
+        if matches!(sent, Ok(_) | Err(NubError::AuditFailed(_))) {
+            self.in_flight = 0;
+        }
+        self.observe();
+        let responses = sent?;
+        match &mut self.on_response {
+            Some(callback) => responses.into_iter().for_each(callback),
+            None => self.responses.extend(responses),
+        }
+        Ok(())
+    }
+
+    /// The replies collected so far, leaving none behind
+    pub fn take_responses(&mut self) -> Vec<Response> {
+        std::mem::take(&mut self.responses)
+    }
+
+    /// Flush the rest of the queue and return the collected replies
+    ///
+    /// Empty when replies go to an [`AutoPipeline::on_response`] callback.
+    pub fn finish(mut self) -> Result<Vec<Response>, NubError> {
+        self.flush()?;
+        Ok(self.take_responses())
+    }
+}
+
+impl<'a> Drop for AutoPipeline<'a> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::tests::mock_node;
+    use crate::CommandObserver;
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_auto_pipeline_flushes_at_count() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        let mut pipeline = client.auto_pipeline(3, usize::MAX);
+        for i in 0..7 {
+            pipeline.set(&format!("auto:{}", i), &i.to_string(), None).unwrap();
+        }
+        assert_eq!(pipeline.queued_len(), 1);
+        assert_eq!(pipeline.take_responses().len(), 6);
+
+        pipeline.get("auto:6").unwrap();
+        pipeline.incr("auto:counter").unwrap();
+        let responses = pipeline.finish().unwrap();
+        assert_eq!(responses[0], Response::Simple("OK".to_string()));
+        assert_eq!(responses[1], Response::Bulk(Some("6".to_string())));
+        assert_eq!(responses.len(), 3);
+
+        let keys: Vec<String> = (0..7).map(|i| format!("auto:{}", i)).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).chain(["auto:counter"]).collect();
+        assert_eq!(client.del_many(&keys).unwrap(), 8);
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_queue_and_in_flight_counts() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<(usize, usize)>>);
+        impl CommandObserver for Recorder {
+            fn on_command(&self, _: &str, _: Duration, _: Result<&Response, &NubError>) {}
+            fn on_pipeline(&self, queued: usize, in_flight: usize) {
+                self.0.lock().unwrap().push((queued, in_flight));
+            }
+        }
+
+        let (addr, _) = mock_node(|_| "OK".to_string());
+        let recorder = Arc::new(Recorder::default());
+        let mut client = NubDB::builder().observer(recorder.clone()).connect(&addr).unwrap();
+        let mut pipeline = client.auto_pipeline(2, usize::MAX);
+        pipeline.set("a", "1", None).unwrap();
+        assert_eq!((pipeline.queued_len(), pipeline.inflight_len()), (1, 0));
+        pipeline.set("b", "2", None).unwrap().set("c", "3", None).unwrap();
+        assert_eq!((pipeline.queued_len(), pipeline.inflight_len()), (1, 0));
+        pipeline.finish().unwrap();
+        assert_eq!(*recorder.0.lock().unwrap(), vec![(1, 0), (0, 2), (0, 0), (1, 0), (0, 1), (0, 0)]);
+
+        // Replies that never arrive leave their commands in flight
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let _ = socket.read(&mut [0; 64]);
+        });
+        let mut client = NubDB::connect(&addr).unwrap();
+        let mut pipeline = client.auto_pipeline(10, usize::MAX);
+        pipeline.get("a").unwrap().get("b").unwrap();
+        assert!(pipeline.flush().is_err());
+        assert_eq!((pipeline.queued_len(), pipeline.inflight_len()), (0, 2));
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_auto_pipeline_flushes_at_bytes_and_on_drop() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        let mut seen = Vec::new();
+        {
+            let mut pipeline = client.auto_pipeline(usize::MAX, 40)
+                .on_response(|response| seen.push(response));
+            pipeline.set("auto:bytes:1", &"x".repeat(50), None).unwrap();
+            assert_eq!(pipeline.queued_len(), 0);
+            pipeline.set("auto:bytes:2", "short", None).unwrap();
+            assert_eq!(pipeline.queued_len(), 1);
+            assert!(matches!(pipeline.get(""), Err(NubError::InvalidKey(_))));
+        }
+        assert_eq!(seen.len(), 2);
+        assert_eq!(client.get("auto:bytes:2").unwrap().as_deref(), Some("short"));
+
+        client.del_many(&["auto:bytes:1", "auto:bytes:2"]).unwrap();
+        client.close().unwrap();
+    }
+}
@@ -0,0 +1,472 @@
+//! Builder for configuring a client before connecting
+
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "compression")]
+use super::CompressionConfig;
+#[cfg(feature = "serde")]
+use super::Format;
+use super::stream::Stream;
+use super::{
+    AuditEvent, AuditHook, CircuitBreaker, CommandObserver, LineEnding, NubDB, NubError, Protocol, QuoteStyle,
+    RetryPolicy, Transport, validate_key,
+};
+
+/// Connection settings, kept on the client so reconnects reuse them
+#[derive(Clone)]
+pub(crate) struct Config {
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) tcp_nodelay: bool,
+    pub(crate) send_buffer_size: Option<usize>,
+    pub(crate) recv_buffer_size: Option<usize>,
+    pub(crate) keepalive: Option<Duration>,
+    pub(crate) max_response_bytes: usize,
+    pub(crate) max_key_length: usize,
+    pub(crate) max_value_length: usize,
+    pub(crate) keep_raw_responses: bool,
+    pub(crate) auto_reconnect: bool,
+    pub(crate) lazy: bool,
+    pub(crate) retry: Option<RetryPolicy>,
+    pub(crate) circuit_breaker: Option<CircuitBreaker>,
+    pub(crate) observer: Option<Arc<dyn CommandObserver>>,
+    pub(crate) audit: Option<Arc<AuditHook>>,
+    #[cfg(feature = "tracing")]
+    pub(crate) trace_keys: bool,
+    pub(crate) protocol: Option<Arc<dyn Protocol>>,
+    pub(crate) quote_style: QuoteStyle,
+    pub(crate) line_ending: LineEnding,
+    pub(crate) password: Option<String>,
+    pub(crate) db: Option<u32>,
+    /// The CLIENT ID invalidations go to, set by `client_tracking`
+    pub(crate) tracking: Option<i64>,
+    #[cfg(feature = "compression")]
+    pub(crate) compression: Option<CompressionConfig>,
+    #[cfg(feature = "serde")]
+    pub(crate) value_format: Format,
+    #[cfg(feature = "tls")]
+    pub(crate) tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            connect_timeout: None,
+            read_timeout: None,
+            // Every command is a small write followed by a wait for the reply.
+            // With Nagle's algorithm on, a command split across writes can sit
+            // in the kernel until the server's delayed ACK fires, adding ~40ms
+            // per round trip.
+            tcp_nodelay: true,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            keepalive: None,
+            max_response_bytes: NubDBBuilder::DEFAULT_MAX_RESPONSE_BYTES,
+            max_key_length: NubDBBuilder::DEFAULT_MAX_KEY_LENGTH,
+            max_value_length: NubDBBuilder::DEFAULT_MAX_VALUE_LENGTH,
+            keep_raw_responses: false,
+            auto_reconnect: false,
+            lazy: false,
+            retry: None,
+            circuit_breaker: None,
+            observer: None,
+            audit: None,
+            #[cfg(feature = "tracing")]
+            trace_keys: false,
+            protocol: None,
+            quote_style: QuoteStyle::Double,
+            line_ending: LineEnding::Lf,
+            password: None,
+            db: None,
+            tracking: None,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "serde")]
+            value_format: Format::Json,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    /// Check `key` can be sent and is within [`NubDBBuilder::max_key_length`]
+    pub(crate) fn check_key(&self, key: &str) -> Result<(), NubError> {
+        validate_key(key)?;
+        if key.len() > self.max_key_length {
+            return Err(NubError::KeyTooLong(key.len(), self.max_key_length));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_keys(&self, keys: &[&str]) -> Result<(), NubError> {
+        keys.iter().try_for_each(|key| self.check_key(key))
+    }
+
+    /// Check a value of `len` bytes is within [`NubDBBuilder::max_value_length`]
+    pub(crate) fn check_value(&self, len: usize) -> Result<(), NubError> {
+        if len > self.max_value_length {
+            return Err(NubError::ValueTooLong(len, self.max_value_length));
+        }
+        Ok(())
+    }
+}
+
+/// Configures and opens a [`NubDB`] connection
+///
+/// Created by [`NubDB::builder`]. Every option defaults to the behaviour of
+/// [`NubDB::connect`].
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use nubdb::NubDB;
+/// let client = NubDB::builder()
+///     .connect_timeout(Duration::from_secs(2))
+///     .read_timeout(Duration::from_secs(5))
+///     .tcp_nodelay(true)
+///     .auto_reconnect(true)
+///     .connect("localhost:6379")?;
+/// # Ok::<(), nubdb::NubError>(())
+/// ```
+#[derive(Clone, Default)]
+pub struct NubDBBuilder {
+    pub(crate) config: Config,
+}
+
+impl NubDBBuilder {
+    /// The largest reply read by default, 512 MiB
+    pub const DEFAULT_MAX_RESPONSE_BYTES: usize = 512 * 1024 * 1024;
+
+    /// The longest key sent by default, 4 KiB, the most the server's
+    /// append-only file reads back
+    pub const DEFAULT_MAX_KEY_LENGTH: usize = 4096;
+
+    /// The longest value written by default, 1 MiB, the most the server's
+    /// append-only file reads back
+    pub const DEFAULT_MAX_VALUE_LENGTH: usize = 1024 * 1024;
+
+    /// Give up connecting after `timeout`
+    ///
+    /// When the host resolves to several addresses each is tried in turn, and
+    /// the timeout applies to each attempt separately.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail commands with `NubError::Timeout` if no reply arrives within `timeout`
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the socket, on by default
+    pub fn tcp_nodelay(mut self, enabled: bool) -> Self {
+        self.config.tcp_nodelay = enabled;
+        self
+    }
+
+    /// Ask the kernel for a socket send buffer (`SO_SNDBUF`) of `bytes`
+    ///
+    /// By default the operating system's size is used, typically 16 KiB to
+    /// 64 KiB and grown automatically on Linux. The kernel treats the size as
+    /// a request: Linux doubles it for bookkeeping and clamps it to
+    /// `net.core.wmem_max`, and macOS to `kern.ipc.maxsockbuf`, without
+    /// reporting an error. Setting a size turns off Linux's automatic tuning
+    /// for the socket.
+    pub fn send_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.send_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Ask the kernel for a socket receive buffer (`SO_RCVBUF`) of `bytes`
+    ///
+    /// The same defaults and caveats apply as for
+    /// [`NubDBBuilder::send_buffer_size`], with Linux clamping to
+    /// `net.core.rmem_max`. The client's own read buffer grows to match, so
+    /// large replies are read in as few system calls as possible; it never
+    /// shrinks below its default of 8 KiB.
+    pub fn recv_buffer_size(mut self, bytes: usize) -> Self {
+        self.config.recv_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Turn on TCP keepalive, probing the server once the connection has
+    /// been idle for `idle`
+    ///
+    /// Off by default. Keepalive lets the kernel notice a peer that vanished
+    /// without closing the connection, for example behind a NAT that dropped
+    /// the flow, and reset the socket. The next command then fails at once
+    /// with an I/O error instead of waiting on a dead connection, so
+    /// [`NubDBBuilder::auto_reconnect`] or [`NubDBBuilder::retry`] can take
+    /// over. How often unanswered probes are repeated, and how many are sent
+    /// before giving up, are left at the operating system's defaults (75
+    /// seconds and 9 probes on Linux).
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.config.keepalive = Some(idle);
+        self
+    }
+
+    /// Fail a reply larger than `limit` bytes with `NubError::ResponseTooLarge`
+    ///
+    /// Guards against a reply too large to hold, such as `KEYS *` on a huge
+    /// database or a broken server sending garbage. The limit counts the
+    /// bytes of one reply as sent, framing included; each reply of a
+    /// pipeline is counted separately. Values copied out with
+    /// [`NubDB::get_into`] are never held in memory and are not limited.
+    /// Defaults to [`NubDBBuilder::DEFAULT_MAX_RESPONSE_BYTES`].
+    pub fn max_response_bytes(mut self, limit: usize) -> Self {
+        self.config.max_response_bytes = limit;
+        self
+    }
+
+    /// Fail a command on a key longer than `limit` bytes with
+    /// `NubError::KeyTooLong`, before anything is sent
+    ///
+    /// The server accepts longer keys, but then fails to replay its
+    /// append-only file on restart, so they are rejected here unless the
+    /// limit is raised for a server built differently. Defaults to
+    /// [`NubDBBuilder::DEFAULT_MAX_KEY_LENGTH`].
+    pub fn max_key_length(mut self, limit: usize) -> Self {
+        self.config.max_key_length = limit;
+        self
+    }
+
+    /// Fail a write of a value longer than `limit` bytes with
+    /// `NubError::ValueTooLong`, before anything is sent
+    ///
+    /// Counts the value as given, before any compression. Defaults to
+    /// [`NubDBBuilder::DEFAULT_MAX_VALUE_LENGTH`], for the same reason as
+    /// [`NubDBBuilder::max_key_length`].
+    pub fn max_value_length(mut self, limit: usize) -> Self {
+        self.config.max_value_length = limit;
+        self
+    }
+
+    /// Keep a copy of the last reply as the server sent it, off by default
+    ///
+    /// For debugging a server that replies in a form the client does not
+    /// expect: see [`NubDB::last_raw_response`]. Each reply is copied once
+    /// more as it is read, so leave this off otherwise.
+    pub fn keep_raw_responses(mut self, enabled: bool) -> Self {
+        self.config.keep_raw_responses = enabled;
+        self
+    }
+
+    /// Put off connecting until the first command, off by default
+    ///
+    /// [`NubDBBuilder::connect`] then only sets the client up, so it succeeds
+    /// even while the server is unreachable. The first command opens the
+    /// connection and sends AUTH and SELECT as configured, and a failure
+    /// surfaces as that command's error; with [`NubDBBuilder::retry`] set,
+    /// commands the policy covers keep trying to connect as they would to
+    /// reconnect. Until a command succeeds in connecting, the next one tries
+    /// again.
+    pub fn lazy(mut self, enabled: bool) -> Self {
+        self.config.lazy = enabled;
+        self
+    }
+
+    /// Reconnect and retry once when a command fails with an I/O error,
+    /// see [`NubDB::set_auto_reconnect`]
+    pub fn auto_reconnect(mut self, enabled: bool) -> Self {
+        self.config.auto_reconnect = enabled;
+        self
+    }
+
+    /// Retry commands that fail on the network, see [`RetryPolicy`]
+    ///
+    /// Takes the place of [`NubDBBuilder::auto_reconnect`] for the commands
+    /// the policy covers.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.config.retry = Some(policy);
+        self
+    }
+
+    /// Stop reconnecting for a while after repeated connect failures, see
+    /// [`CircuitBreaker`]
+    ///
+    /// Keeps [`NubDBBuilder::auto_reconnect`] and [`NubDBBuilder::retry`]
+    /// from trying to connect on every command while the server is down.
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.config.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Report the name, duration and outcome of every command to `observer`
+    ///
+    /// Covers commands sent one at a time; pipelines, transactions,
+    /// subscriptions and the binary and streaming value methods are not
+    /// reported.
+    pub fn observer(mut self, observer: Arc<dyn CommandObserver>) -> Self {
+        self.config.observer = Some(observer);
+        self
+    }
+
+    /// Report every write to `hook` before it is sent and after its reply
+    /// arrives, see [`AuditEvent`]
+    ///
+    /// Unlike [`NubDBBuilder::observer`], the hook can stop a command: if it
+    /// returns an error for `AuditEvent::Before`, the command is not sent and
+    /// fails with `NubError::AuditFailed`. An error for `AuditEvent::After`
+    /// is returned in place of the command's own result, although the
+    /// command has run. Reads are never reported, and without a hook writes
+    /// cost nothing extra.
+    ///
+    /// Covers writes sent one at a time, in pipelines and in transactions,
+    /// including those sent with [`NubDB::command`] and
+    /// [`NubDB::raw_command`], and the binary value methods. A command queued
+    /// in a transaction is reported when queued, with the outcome of
+    /// queueing it.
+    pub fn audit<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&AuditEvent<'_>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> + Send + Sync + 'static,
+    {
+        self.config.audit = Some(Arc::new(hook));
+        self
+    }
+
+    /// Record the key on each command's `tracing` span, off by default
+    ///
+    /// Every command runs inside an `nubdb.command` span carrying the
+    /// command name, duration and outcome. Keys are left out unless enabled
+    /// here, as one span field value per key overwhelms most backends.
+    /// Passwords are never recorded.
+    #[cfg(feature = "tracing")]
+    pub fn trace_keys(mut self, enabled: bool) -> Self {
+        self.config.trace_keys = enabled;
+        self
+    }
+
+    /// Frame commands and replies with `protocol` instead of [`TextProtocol`]
+    ///
+    /// Used by every command sent one at a time, pipelines and transactions,
+    /// and for reading subscription messages. The binary and streaming value
+    /// methods, `dump` and `restore` frame their payloads themselves and keep
+    /// using the text protocol.
+    ///
+    /// [`TextProtocol`]: super::TextProtocol
+    pub fn protocol(mut self, protocol: Arc<dyn Protocol>) -> Self {
+        self.config.protocol = Some(protocol);
+        self
+    }
+
+    /// Quote values sent with SET and the other string writes in `style`,
+    /// stripping the same quotes from values read back, see [`QuoteStyle`]
+    ///
+    /// Defaults to [`QuoteStyle::Double`]. Covers every value, hash value,
+    /// list element and set or sorted set member sent, in pipelines,
+    /// transactions and clusters too, as well as `ping_message`, `publish`
+    /// and the arguments of [`NubDB::command`] and [`NubDB::execute`]. The
+    /// exceptions are the binary value methods, which send raw bytes, AUTH
+    /// passwords, and the async client, which always uses double quotes.
+    ///
+    /// The quotes are stripped from every text reply as it is read, so
+    /// values come back as written from each method, pipeline and
+    /// transaction alike. Replies read by a custom [`NubDBBuilder::protocol`]
+    /// are left as it decodes them.
+    pub fn quote_style(mut self, style: QuoteStyle) -> Self {
+        self.config.quote_style = style;
+        self
+    }
+
+    /// End each command line with `ending`, [`LineEnding::Lf`] by default
+    ///
+    /// For server builds that only accept `\r\n`. Covers every command,
+    /// including the header and payload terminator of the binary value
+    /// methods. A [`NubDBBuilder::protocol`] is passed the ending and frames
+    /// commands as it sees fit; [`TextProtocol`] follows it. Replies are
+    /// read the same either way.
+    ///
+    /// [`TextProtocol`]: super::TextProtocol
+    pub fn line_ending(mut self, ending: LineEnding) -> Self {
+        self.config.line_ending = ending;
+        self
+    }
+
+    /// Send AUTH with `password` right after connecting
+    ///
+    /// A rejected password fails [`NubDBBuilder::connect`] with
+    /// `NubError::AuthFailed`.
+    pub fn password(mut self, password: &str) -> Self {
+        self.config.password = Some(password.to_string());
+        self
+    }
+
+    /// SELECT database `index` right after connecting, see [`NubDB::select`]
+    pub fn db(mut self, index: u32) -> Self {
+        self.config.db = Some(index);
+        self
+    }
+
+    /// Compress large values before storing them, see the crate docs
+    ///
+    /// Applies to values written with `set`, `setex`, `psetex`, `set_nx`,
+    /// `mset` and `get_set`, and to values read with `get`, `mget`,
+    /// `get_set` and `get_del`. Commands that work on the stored string in
+    /// place, such as `append` and `strlen`, see the compressed form, as do
+    /// pipelines and transactions. [`NubDB::set_raw`] writes a single value
+    /// uncompressed.
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, config: CompressionConfig) -> Self {
+        self.config.compression = Some(config);
+        self
+    }
+
+    /// Serialize values for [`NubDB::set_value`] and [`NubDB::get_value`]
+    /// in `format`, [`Format::Json`] by default
+    #[cfg(feature = "serde")]
+    pub fn value_format(mut self, format: Format) -> Self {
+        self.config.value_format = format;
+        self
+    }
+
+    /// Connect over TLS, see [`NubDB::connect_tls`]
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: rustls::ClientConfig) -> Self {
+        self.config.tls = Some(Arc::new(config));
+        self
+    }
+
+    /// Connect to `addr` with the configured options
+    pub fn connect(self, addr: &str) -> Result<NubDB, NubError> {
+        NubDB::connect_with(addr, self.config)
+    }
+
+    /// Talk to the server over `transport` instead of opening a socket
+    ///
+    /// The read timeout is passed to [`Transport::set_read_timeout`] and the
+    /// session started as [`NubDBBuilder::connect`] does; the socket options
+    /// and TLS settings do not apply. The client cannot reconnect, as there is
+    /// nothing to reopen: [`NubDB::reconnect`] fails with an I/O error of kind
+    /// `Unsupported`.
+    ///
+    /// ```
+    /// # use std::io::{self, Cursor, Read, Write};
+    /// # use nubdb::{NubDB, Transport};
+    /// /// Answers every command with the replies it was given
+    /// struct Scripted(Cursor<Vec<u8>>);
+    ///
+    /// impl Read for Scripted {
+    ///     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+    /// }
+    ///
+    /// impl Write for Scripted {
+    ///     fn write(&mut self, buf: &[u8]) -> io::Result<usize> { Ok(buf.len()) }
+    ///     fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    /// }
+    ///
+    /// impl Transport for Scripted {}
+    ///
+    /// let replies = Scripted(Cursor::new(b"OK\n\"hello\"\n".to_vec()));
+    /// let mut client = NubDB::builder().connect_transport(replies)?;
+    /// assert!(client.set("greeting", "hello", None)?);
+    /// assert_eq!(client.get("greeting")?.as_deref(), Some("hello"));
+    /// # Ok::<(), nubdb::NubError>(())
+    /// ```
+    pub fn connect_transport(self, transport: impl Transport + 'static) -> Result<NubDB, NubError> {
+        transport.set_read_timeout(self.config.read_timeout)?;
+        NubDB::start_session(Stream::Custom(Box::new(transport)), "transport", self.config)
+    }
+}
@@ -0,0 +1,216 @@
+//! Reading values into a reused buffer and handing them out as `Bytes`
+
+use std::io::{BufRead, Read};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use super::{reply_error, server_error, NubDB, NubError};
+
+impl NubDB {
+    /// GET a value as `Bytes`, without building a `String` for it
+    ///
+    /// The reply is read into a buffer kept on the client, and the value is
+    /// returned as a slice of that buffer: the quotes around it are skipped
+    /// rather than copied off, and escapes are undone in place. Once every
+    /// `Bytes` from earlier calls has been dropped, the next call reuses the
+    /// same allocation, so a loop of reads settles down to no allocations at
+    /// all. A `Bytes` that is kept keeps its part of the buffer alive, and
+    /// later calls fill the rest or allocate anew.
+    ///
+    /// Values written with compression enabled are decompressed, which does
+    /// allocate.
+    pub fn get_bytes_buf(&mut self, key: &str) -> Result<Option<Bytes>, NubError> {
+        self.config.check_key(key)?;
+        self.ensure_in_sync()?;
+        self.write_line(&format!("GET {}", key))?;
+
+        let mut buf = std::mem::take(&mut self.read_buf);
+        buf.clear();
+        let value = self.read_value_into(&mut buf);
+        self.read_buf = buf;
+        value
+    }
+
+    /// Read one value reply into `buf` and split the value off it
+    fn read_value_into(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>, NubError> {
+        self.read_raw_line(buf)?;
+        let (start, end) = trim_ascii(buf);
+        let line = &buf[start..end];
+
+        if line == b"(nil)" || line == b"$-1" {
+            return Ok(None);
+        }
+        if let Some(len) = line.strip_prefix(b"$") {
+            let len = std::str::from_utf8(len).ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .ok_or_else(|| NubError::UnexpectedResponse(String::from_utf8_lossy(line).into_owned()))?;
+            self.check_reply_size(len)?;
+            buf.clear();
+            buf.resize(len, 0);
+            let read = self.stream.read_exact(buf);
+            self.track_io(read)?;
+            self.read_terminator(len as u64)?;
+            return self.finish_value(buf.split());
+        }
+        if let Some(msg) = std::str::from_utf8(line).ok().and_then(server_error) {
+            return Err(reply_error(msg));
+        }
+
+        let mut value = buf.split();
+        value.truncate(end);
+        value.advance(start);
+        if value.len() >= 2 && value.starts_with(b"\"") && value.ends_with(b"\"") {
+            value.advance(1);
+            value.truncate(value.len() - 1);
+            let len = unescape_in_place(&mut value);
+            value.truncate(len);
+        }
+        self.finish_value(value)
+    }
+
+    /// Decompress `value` if the client is configured to
+    fn finish_value(&self, value: BytesMut) -> Result<Option<Bytes>, NubError> {
+        #[cfg(feature = "compression")]
+        if self.config.compression.is_some() && value.starts_with(super::compression::PREFIX.as_bytes()) {
+            let text = String::from_utf8(value.to_vec())
+                .map_err(|e| NubError::Parse(e.to_string()))?;
+            return Ok(Some(Bytes::from(super::compression::decode(text)?)));
+        }
+        Ok(Some(value.freeze()))
+    }
+
+    /// Append one reply line, newline included, to `buf`
+    fn read_raw_line(&mut self, buf: &mut BytesMut) -> Result<(), NubError> {
+        loop {
+            let filled = self.stream.fill_buf().map(|available| {
+                match available.iter().position(|&b| b == b'\n') {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..=i]);
+                        (i + 1, true)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (available.len(), false)
+                    }
+                }
+            });
+            let (used, complete) = self.track_io(filled)?;
+            if used == 0 {
+                self.broken = true;
+                return Err(NubError::ConnectionClosed);
+            }
+            self.stream.consume(used);
+            self.check_reply_size(buf.len())?;
+            if complete {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Give up on a reply of `len` bytes if it is over the configured limit
+    fn check_reply_size(&mut self, len: usize) -> Result<(), NubError> {
+        if len > self.config.max_response_bytes {
+            self.broken = true;
+            return Err(NubError::ResponseTooLarge(self.config.max_response_bytes));
+        }
+        Ok(())
+    }
+}
+
+/// The start and end of `bytes` without surrounding whitespace
+fn trim_ascii(bytes: &[u8]) -> (usize, usize) {
+    let start = bytes.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(bytes.len());
+    let end = bytes.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(start, |i| i + 1);
+    (start, end)
+}
+
+/// Undo value escaping within `value`, returning the unescaped length
+///
+/// Matches `unescape_value`: unknown escapes and a trailing backslash are
+/// kept as they are.
+fn unescape_in_place(value: &mut [u8]) -> usize {
+    let (mut read, mut write) = (0, 0);
+    while read < value.len() {
+        let escaped = match (value[read], value.get(read + 1)) {
+            (b'\\', Some(b'"')) => Some(b'"'),
+            (b'\\', Some(b'\\')) => Some(b'\\'),
+            (b'\\', Some(b'n')) => Some(b'\n'),
+            (b'\\', Some(b'r')) => Some(b'\r'),
+            _ => None,
+        };
+        match escaped {
+            Some(b) => {
+                value[write] = b;
+                read += 2;
+            }
+            None => {
+                value[write] = value[read];
+                read += 1;
+            }
+        }
+        write += 1;
+    }
+    write
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_unescape_in_place() {
+        for raw in [r#"plain"#, r#"say \"hi\""#, r"a\\b\nc\rd", r"unknown \t", r"trailing \"] {
+            let mut bytes = raw.as_bytes().to_vec();
+            let len = unescape_in_place(&mut bytes);
+            assert_eq!(&bytes[..len], crate::unescape_value(raw).as_bytes(), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_get_bytes_buf() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        client.set("bytesbuf:key", "line \"one\"\nline two", None).unwrap();
+
+        let value = client.get_bytes_buf("bytesbuf:key").unwrap().unwrap();
+        assert_eq!(&value[..], b"line \"one\"\nline two");
+        assert_eq!(client.get_bytes_buf("bytesbuf:missing").unwrap(), None);
+
+        // Values handed out earlier stay valid while the buffer is reused
+        for _ in 0..10 {
+            assert_eq!(client.get_bytes_buf("bytesbuf:key").unwrap().as_ref(), Some(&value));
+        }
+        client.set("bytesbuf:key", "short", None).unwrap();
+        assert_eq!(&client.get_bytes_buf("bytesbuf:key").unwrap().unwrap()[..], b"short");
+        assert_eq!(&value[..], b"line \"one\"\nline two");
+
+        assert!(client.ping().is_ok());
+        client.delete("bytesbuf:key").unwrap();
+        client.close().unwrap();
+    }
+
+    #[test]
+    #[ignore = "benchmark; needs a running server"]
+    fn bench_get_bytes_buf_vs_get() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        client.set("bench:bytesbuf", &"v".repeat(512), None).unwrap();
+        let n = 10_000;
+
+        let start = Instant::now();
+        for _ in 0..n {
+            client.get("bench:bytesbuf").unwrap();
+        }
+        let strings = start.elapsed();
+
+        let start = Instant::now();
+        for _ in 0..n {
+            client.get_bytes_buf("bench:bytesbuf").unwrap();
+        }
+        let buffered = start.elapsed();
+
+        println!("{} GETs: get {:?}, get_bytes_buf {:?}", n, strings, buffered);
+        client.delete("bench:bytesbuf").unwrap();
+        client.close().unwrap();
+    }
+}
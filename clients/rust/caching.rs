@@ -0,0 +1,549 @@
+//! A local cache in front of a client for hot keys
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::stream::Stream;
+use super::{NubDB, NubError, Response};
+
+/// The channel a server that tracks keys publishes their invalidations on
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// A client that keeps recently read values in process memory
+///
+/// [`get`](Self::get) answers from the local cache when it can and asks the
+/// server otherwise, remembering the value for next time. At most `capacity`
+/// values are kept; when full, the least recently read one is dropped.
+///
+/// Each cached value is kept for `local_ttl`, or until the key's expiry on
+/// the server if that is sooner, so the cache never serves a key after the
+/// server would have expired it. Filling the cache on a miss costs one more
+/// round trip, a `TTL`, to learn that expiry.
+///
+/// [`set`](Self::set) and [`delete`](Self::delete) drop the local copy of a
+/// key. Writes made through [`client`](Self::client), or by other clients,
+/// are not seen until the local copy expires; use
+/// [`get_fresh`](Self::get_fresh) for a read that must see them, or turn on
+/// [`tracking`](Self::tracking) to have the server announce them.
+///
+/// With [`stale_while_error`](Self::stale_while_error) on, a read that
+/// cannot reach the server is answered from the last copy read, however
+/// old, instead of failing.
+pub struct CachingNubDB {
+    client: NubDB,
+    cache: Lru,
+    local_ttl: Duration,
+    tracking: Option<Tracking>,
+}
+
+/// A value read through [`CachingNubDB::get_cached`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedValue {
+    pub value: String,
+    /// The server could not be reached, so this is an expired local copy
+    /// that may no longer match the server
+    pub stale: bool,
+}
+
+impl CachingNubDB {
+    /// Cache up to `capacity` values from `client`, each for at most `local_ttl`
+    pub fn new(client: NubDB, capacity: usize, local_ttl: Duration) -> Self {
+        CachingNubDB { client, cache: Lru::new(capacity), local_ttl, tracking: None }
+    }
+
+    /// Have the server say when a cached key changes, and drop its local
+    /// copy then, off by default
+    ///
+    /// Needs a server that supports `CLIENT TRACKING`. The server only sends
+    /// invalidations to a connection subscribed to them, which cannot also
+    /// run commands, so turning tracking on opens a second connection to the
+    /// same server with the same settings. A background thread reads the
+    /// invalidations arriving there, and every read through the cache first
+    /// drops the copies they name. Every local copy is dropped when tracking
+    /// is turned on, as the server knows nothing of keys read before.
+    ///
+    /// A write by any client, including through [`client`](Self::client),
+    /// is then seen by the first read after its invalidation arrives. A read
+    /// in between may still be answered from the old copy, so reads are
+    /// eventually consistent rather than up to date; [`get_fresh`](Self::get_fresh)
+    /// is still the way to read what the server holds right now. Copies
+    /// still expire after `local_ttl`, as a bound should an invalidation
+    /// never arrive.
+    ///
+    /// When invalidations may have been missed, because either connection
+    /// was lost or [`NubDB::reset`] was sent, every local copy is dropped
+    /// and the next read starts tracking again, failing if it cannot.
+    pub fn tracking(&mut self, enabled: bool) -> Result<(), NubError> {
+        if enabled == self.tracking.is_some() {
+            return Ok(());
+        }
+        if enabled {
+            self.cache.clear();
+            self.tracking = Some(Tracking::start(&mut self.client, self.cache.capacity)?);
+            Ok(())
+        } else {
+            self.tracking = None;
+            self.client.client_tracking(None)
+        }
+    }
+
+    /// Serve expired local copies when the server cannot be reached, off by
+    /// default
+    ///
+    /// When on, expired copies are kept until evicted instead of being
+    /// dropped. A read that misses the cache and then fails with a network
+    /// error, a timeout, a closed connection or an open circuit breaker is
+    /// answered from the expired copy of the key, if there is one, rather
+    /// than failing. [`get_cached`](Self::get_cached) tells such values
+    /// apart. Other errors, and every write, fail as usual.
+    pub fn stale_while_error(mut self, enabled: bool) -> Self {
+        self.cache.keep_expired = enabled;
+        self
+    }
+
+    /// GET value by key, from the local cache if it holds a copy
+    ///
+    /// May return an expired copy under
+    /// [`stale_while_error`](Self::stale_while_error).
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        Ok(self.get_cached(key)?.map(|cached| cached.value))
+    }
+
+    /// GET value by key like [`get`](Self::get), saying whether the value is
+    /// a stale copy served because the server could not be reached
+    pub fn get_cached(&mut self, key: &str) -> Result<Option<CachedValue>, NubError> {
+        self.apply_invalidations()?;
+        let now = Instant::now();
+        if let Some(value) = self.cache.get(key, now) {
+            return Ok(Some(CachedValue { value, stale: false }));
+        }
+
+        match self.fetch(key, now) {
+            Err(e) if self.cache.keep_expired && unreachable(&e) => match self.cache.get_expired(key) {
+                Some(value) => Ok(Some(CachedValue { value, stale: true })),
+                None => Err(e),
+            },
+            result => Ok(result?.map(|value| CachedValue { value, stale: false })),
+        }
+    }
+
+    /// Drop the local copies the server has said are out of date, starting
+    /// tracking again if invalidations may have been missed
+    fn apply_invalidations(&mut self) -> Result<(), NubError> {
+        let Some(tracking) = &mut self.tracking else { return Ok(()) };
+        let (keys, all, lost) = {
+            let mut pending = tracking.pending.lock().unwrap();
+            (std::mem::take(&mut pending.keys), std::mem::take(&mut pending.all), pending.lost)
+        };
+        let resumed = tracking.session != self.client.sessions;
+        if all || lost || resumed {
+            self.cache.clear();
+        } else {
+            keys.iter().for_each(|key| self.cache.remove(key));
+        }
+
+        if lost {
+            *tracking = Tracking::start(&mut self.client, self.cache.capacity)?;
+        } else if resumed {
+            // A reconnect turns tracking on again by itself, RESET does not
+            if self.client.config.tracking != Some(tracking.redirect) {
+                self.client.client_tracking(Some(tracking.redirect))?;
+            }
+            tracking.session = self.client.sessions;
+        }
+        Ok(())
+    }
+
+    /// Read `key` from the server into the cache
+    fn fetch(&mut self, key: &str, now: Instant) -> Result<Option<String>, NubError> {
+        let session = self.client.sessions;
+        // Ask for the expiry first, so a copy can only expire early
+        let server_ttl = match self.client.ttl_duration(key) {
+            Ok(ttl) => ttl,
+            Err(NubError::KeyNotFound(_)) => {
+                self.cache.remove(key);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let value = self.client.get(key)?;
+        // Invalidations may have been missed, which the next read sorts out
+        if self.tracking.is_some() && self.client.sessions != session {
+            return Ok(value);
+        }
+        // TTL is in whole seconds and may be rounded up, so allow for a second less
+        let ttl = server_ttl
+            .map_or(self.local_ttl, |ttl| ttl.saturating_sub(Duration::from_secs(1)).min(self.local_ttl));
+        match value.as_ref().filter(|_| !ttl.is_zero()) {
+            Some(value) => self.cache.insert(key, value.clone(), now + ttl),
+            None => self.cache.remove(key),
+        }
+        Ok(value)
+    }
+
+    /// GET value by key from the server, bypassing the local cache
+    ///
+    /// Any local copy is dropped, so later reads fetch the value again.
+    pub fn get_fresh(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        self.cache.remove(key);
+        self.client.get(key)
+    }
+
+    /// SET key-value pair, dropping the local copy
+    pub fn set(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.cache.remove(key);
+        self.client.set(key, value, ttl)
+    }
+
+    /// DELETE key, dropping the local copy
+    pub fn delete(&mut self, key: &str) -> Result<bool, NubError> {
+        self.cache.remove(key);
+        self.client.delete(key)
+    }
+
+    /// Drop the local copy of `key`
+    pub fn invalidate(&mut self, key: &str) {
+        self.cache.remove(key);
+    }
+
+    /// Drop every local copy
+    pub fn invalidate_all(&mut self) {
+        self.cache.clear();
+    }
+
+    /// The wrapped client, for commands that do not go through the cache
+    pub fn client(&mut self) -> &mut NubDB {
+        &mut self.client
+    }
+
+    /// Unwrap the client, dropping the cache
+    pub fn into_inner(self) -> NubDB {
+        self.client
+    }
+}
+
+/// The second connection a tracking cache is told of changes on
+struct Tracking {
+    /// CLIENT ID of the connection invalidations are sent to
+    redirect: i64,
+    pending: Arc<Mutex<Invalidations>>,
+    /// The socket under that connection, shut down to end its thread
+    socket: Stream,
+    /// The client's session tracking was last turned on in
+    session: u64,
+}
+
+/// Invalidations read but not yet applied to the cache
+#[derive(Default)]
+struct Invalidations {
+    keys: Vec<String>,
+    /// Every key was invalidated, or more than the cache can hold
+    all: bool,
+    /// The connection was lost, and invalidations with it
+    lost: bool,
+}
+
+impl Tracking {
+    /// Open a connection for invalidations and have `client`'s go there
+    fn start(client: &mut NubDB, capacity: usize) -> Result<Self, NubError> {
+        let mut redirect = client.connect_again()?;
+        redirect.set_read_timeout(None)?;
+        let id = redirect.client_id()?;
+        redirect.send_subscribe(&[INVALIDATE_CHANNEL])?;
+
+        let pending = Arc::new(Mutex::new(Invalidations::default()));
+        let mut tracking = Tracking {
+            redirect: id,
+            pending: pending.clone(),
+            socket: redirect.stream.get_ref().try_clone_socket()?,
+            session: 0,
+        };
+        thread::spawn(move || read_invalidations(redirect, pending, capacity));
+
+        client.client_tracking(Some(id))?;
+        tracking.session = client.sessions;
+        Ok(tracking)
+    }
+}
+
+impl Drop for Tracking {
+    fn drop(&mut self) {
+        let _ = self.socket.shutdown();
+    }
+}
+
+/// Queue the invalidations arriving on `redirect` until it is shut down
+fn read_invalidations(mut redirect: NubDB, pending: Arc<Mutex<Invalidations>>, capacity: usize) {
+    loop {
+        let invalidated = redirect.read_reply().and_then(invalidated_keys);
+        let mut pending = pending.lock().unwrap();
+        match invalidated {
+            Ok(Some(keys)) if !pending.all => pending.keys.extend(keys),
+            Ok(Some(_)) => {}
+            Ok(None) => pending.all = true,
+            Err(_) => {
+                pending.lost = true;
+                return;
+            }
+        }
+        if pending.keys.len() > capacity {
+            pending.keys.clear();
+            pending.all = true;
+        }
+    }
+}
+
+/// The keys an invalidation message names, or `None` for every key
+///
+/// Other pushes, such as the subscribe confirmation, name no keys.
+fn invalidated_keys(push: Response) -> Result<Option<Vec<String>>, NubError> {
+    let mut items = push.into_array()?.into_iter();
+    match (items.next(), items.next(), items.next()) {
+        (Some(Response::Simple(kind)), Some(_), Some(payload)) if kind == "message" => match payload {
+            Response::Bulk(None) => Ok(None),
+            Response::Array(keys) => keys.into_iter().map(Response::into_string).collect::<Result<_, _>>().map(Some),
+            key => Ok(Some(vec![key.into_string()?])),
+        },
+        _ => Ok(Some(Vec::new())),
+    }
+}
+
+/// Whether `err` means the server could not be reached, rather than that
+/// it answered with an error
+fn unreachable(err: &NubError) -> bool {
+    matches!(
+        err,
+        NubError::Io(_) | NubError::Timeout | NubError::ConnectionClosed | NubError::CircuitOpen | NubError::Desynchronized
+    )
+}
+
+/// Values by key, dropping the least recently read when full
+struct Lru {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+    /// Keys by the tick they were last read at, oldest first
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    /// Keep expired entries for `get_expired` instead of dropping them on read
+    keep_expired: bool,
+}
+
+struct Entry {
+    value: String,
+    expires_at: Instant,
+    tick: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Lru { capacity, entries: HashMap::new(), order: BTreeMap::new(), tick: 0, keep_expired: false }
+    }
+
+    fn get(&mut self, key: &str, now: Instant) -> Option<String> {
+        let entry = self.entries.get_mut(key)?;
+        if now >= entry.expires_at {
+            if !self.keep_expired {
+                self.remove(key);
+            }
+            return None;
+        }
+
+        self.tick += 1;
+        self.order.remove(&entry.tick);
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.to_string());
+        Some(entry.value.clone())
+    }
+
+    /// The entry for `key` even if it has expired
+    fn get_expired(&self, key: &str) -> Option<String> {
+        self.entries.get(key).map(|entry| entry.value.clone())
+    }
+
+    fn insert(&mut self, key: &str, value: String, expires_at: Instant) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(key);
+        while self.entries.len() >= self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else { break };
+            self.entries.remove(&oldest);
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.to_string());
+        self.entries.insert(key.to_string(), Entry { value, expires_at, tick: self.tick });
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{break_socket, mock_node};
+
+    #[test]
+    fn test_lru_evicts_least_recently_read() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(60);
+        let mut lru = Lru::new(2);
+
+        lru.insert("a", "1".to_string(), later);
+        lru.insert("b", "2".to_string(), later);
+        assert_eq!(lru.get("a", now).as_deref(), Some("1"));
+        lru.insert("c", "3".to_string(), later);
+        assert_eq!(lru.get("b", now), None);
+        assert_eq!(lru.get("a", now).as_deref(), Some("1"));
+        assert_eq!(lru.get("c", now).as_deref(), Some("3"));
+
+        // Expired entries are dropped on read
+        assert_eq!(lru.get("c", later), None);
+        assert_eq!(lru.entries.len(), 1);
+        assert_eq!(lru.order.len(), 1);
+
+        // Or kept, to be served stale
+        lru.keep_expired = true;
+        lru.insert("c", "3".to_string(), now);
+        assert_eq!(lru.get("c", later), None);
+        assert_eq!(lru.get_expired("c").as_deref(), Some("3"));
+
+        let mut disabled = Lru::new(0);
+        disabled.insert("a", "1".to_string(), later);
+        assert_eq!(disabled.get("a", now), None);
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_caching_client() {
+        let mut other = NubDB::connect("localhost:6379").unwrap();
+        let mut cached = CachingNubDB::new(NubDB::connect("localhost:6379").unwrap(), 16, Duration::from_secs(60));
+
+        cached.set("caching:hot", "v1", None).unwrap();
+        assert_eq!(cached.get("caching:hot").unwrap().as_deref(), Some("v1"));
+
+        // Served locally until invalidated or bypassed
+        other.set("caching:hot", "v2", None).unwrap();
+        assert_eq!(cached.get("caching:hot").unwrap().as_deref(), Some("v1"));
+        assert_eq!(cached.get_fresh("caching:hot").unwrap().as_deref(), Some("v2"));
+        assert_eq!(cached.get("caching:hot").unwrap().as_deref(), Some("v2"));
+
+        cached.set("caching:hot", "v3", None).unwrap();
+        assert_eq!(cached.get("caching:hot").unwrap().as_deref(), Some("v3"));
+        cached.delete("caching:hot").unwrap();
+        assert_eq!(cached.get("caching:hot").unwrap(), None);
+
+        // The server's expiry bounds the local one
+        cached.set("caching:short", "soon gone", Some(2)).unwrap();
+        assert_eq!(cached.get("caching:short").unwrap().as_deref(), Some("soon gone"));
+        std::thread::sleep(Duration::from_millis(2100));
+        assert_eq!(cached.get("caching:short").unwrap(), None);
+
+        other.close().unwrap();
+        cached.into_inner().close().unwrap();
+    }
+
+    #[test]
+    fn test_invalidated_keys() {
+        let message = |payload| Response::Array(vec![
+            Response::Simple("message".to_string()),
+            Response::Simple(INVALIDATE_CHANNEL.to_string()),
+            payload,
+        ]);
+        let keys = Response::Array(vec![Response::Simple("a".to_string()), Response::Bulk(Some("b c".to_string()))]);
+        assert_eq!(invalidated_keys(message(keys)).unwrap(), Some(vec!["a".to_string(), "b c".to_string()]));
+        assert_eq!(invalidated_keys(message(Response::Simple("a".to_string()))).unwrap(), Some(vec!["a".to_string()]));
+        assert_eq!(invalidated_keys(message(Response::Bulk(None))).unwrap(), None);
+
+        let confirmation = Response::Array(vec![
+            Response::Simple("subscribe".to_string()),
+            Response::Simple(INVALIDATE_CHANNEL.to_string()),
+            Response::Integer(1),
+        ]);
+        assert_eq!(invalidated_keys(confirmation).unwrap(), Some(Vec::new()));
+        assert!(invalidated_keys(Response::Simple("OK".to_string())).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_tracking() {
+        let mut other = NubDB::connect("localhost:6379").unwrap();
+        let mut cached = CachingNubDB::new(NubDB::connect("localhost:6379").unwrap(), 16, Duration::from_secs(60));
+        cached.tracking(true).unwrap();
+        // Invalidations arrive on another connection, so give them a moment
+        fn read_soon(cached: &mut CachingNubDB, expected: Option<&str>) -> Option<String> {
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while cached.get("tracking:k").unwrap().as_deref() != expected && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+            cached.get("tracking:k").unwrap()
+        }
+
+        other.set("tracking:k", "v1", None).unwrap();
+        assert_eq!(cached.get("tracking:k").unwrap().as_deref(), Some("v1"));
+        other.set("tracking:k", "v2", None).unwrap();
+        assert_eq!(read_soon(&mut cached, Some("v2")).as_deref(), Some("v2"));
+
+        // Tracking survives a reconnect, and is turned on again after RESET
+        cached.client().reconnect().unwrap();
+        assert_eq!(cached.get("tracking:k").unwrap().as_deref(), Some("v2"));
+        other.set("tracking:k", "v3", None).unwrap();
+        assert_eq!(read_soon(&mut cached, Some("v3")).as_deref(), Some("v3"));
+        cached.client().reset().unwrap();
+        assert_eq!(cached.get("tracking:k").unwrap().as_deref(), Some("v3"));
+        other.delete("tracking:k").unwrap();
+        assert_eq!(read_soon(&mut cached, None), None);
+
+        // And started again when its connection is lost
+        other.set("tracking:k", "v4", None).unwrap();
+        assert_eq!(cached.get("tracking:k").unwrap().as_deref(), Some("v4"));
+        let pending = cached.tracking.as_ref().unwrap().pending.clone();
+        cached.tracking.as_mut().unwrap().socket.shutdown().unwrap();
+        while !pending.lock().unwrap().lost {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(cached.get("tracking:k").unwrap().as_deref(), Some("v4"));
+        other.set("tracking:k", "v5", None).unwrap();
+        assert_eq!(read_soon(&mut cached, Some("v5")).as_deref(), Some("v5"));
+
+        cached.tracking(false).unwrap();
+        assert_eq!(cached.client().config.tracking, None);
+        other.close().unwrap();
+        cached.into_inner().close().unwrap();
+    }
+
+    #[test]
+    fn test_stale_while_error() {
+        let (addr, _) = mock_node(|cmd| match cmd {
+            "TTL k" => "-1".to_string(),
+            "GET k" => r#""v""#.to_string(),
+            _ => "(nil)".to_string(),
+        });
+        let client = NubDB::connect(&addr).unwrap();
+        let mut cached = CachingNubDB::new(client, 16, Duration::from_millis(50)).stale_while_error(true);
+        let fresh = CachedValue { value: "v".to_string(), stale: false };
+        assert_eq!(cached.get_cached("k").unwrap(), Some(fresh));
+
+        std::thread::sleep(Duration::from_millis(60));
+        break_socket(cached.client());
+        let stale = CachedValue { value: "v".to_string(), stale: true };
+        assert_eq!(cached.get_cached("k").unwrap(), Some(stale));
+        assert_eq!(cached.get("k").unwrap().as_deref(), Some("v"));
+
+        // Nothing to fall back on, and writes always fail
+        assert!(cached.get("other").is_err());
+        assert!(cached.set("k", "w", None).is_err());
+        assert!(cached.get("k").is_err());
+    }
+}
@@ -0,0 +1,115 @@
+//! Failing fast while the server cannot be reached
+
+use std::time::{Duration, Instant};
+
+use super::NubError;
+
+/// When to stop reconnecting to a server that keeps refusing connections
+///
+/// Set with [`NubDBBuilder::circuit_breaker`]. After `failure_threshold`
+/// reconnects in a row fail to open a connection, the circuit opens: for the
+/// next `cooldown`, every reconnect, including the ones automatic reconnects
+/// and retries make, fails at once with `NubError::CircuitOpen` instead of
+/// trying to connect. Once the cooldown is over a single reconnect is tried
+/// again. If it connects the count starts over; if not, the circuit opens
+/// for another cooldown straight away.
+///
+/// Only opening the connection counts, so a rejected AUTH or SELECT never
+/// opens the circuit. The first connect, made by
+/// [`NubDBBuilder::connect`], is not covered.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use nubdb::CircuitBreaker;
+/// // After 5 failed reconnects, fail fast for 10 seconds
+/// let breaker = CircuitBreaker::new(5, Duration::from_secs(10));
+/// ```
+///
+/// [`NubDBBuilder::circuit_breaker`]: super::NubDBBuilder::circuit_breaker
+/// [`NubDBBuilder::connect`]: super::NubDBBuilder::connect
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// Open after `failure_threshold` failed reconnects in a row, and stay
+    /// open for `cooldown`
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker { failure_threshold: failure_threshold.max(1), cooldown }
+    }
+}
+
+/// Failed reconnects counted against a [`CircuitBreaker`]
+#[derive(Debug, Default)]
+pub(crate) struct Circuit {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Circuit {
+    /// Fail with `NubError::CircuitOpen` while the cooldown lasts
+    pub(crate) fn check(&self) -> Result<(), NubError> {
+        match self.open_until {
+            Some(until) if Instant::now() < until => Err(NubError::CircuitOpen),
+            _ => Ok(()),
+        }
+    }
+
+    /// Count the outcome of one attempt to open a connection
+    pub(crate) fn record<T>(&mut self, breaker: &CircuitBreaker, result: &Result<T, NubError>) {
+        if result.is_ok() {
+            *self = Circuit::default();
+            return;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= breaker.failure_threshold {
+            self.open_until = Some(Instant::now() + breaker.cooldown);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    use crate::tests::{break_socket, mock_node};
+    use crate::NubDB;
+
+    /// An address nothing is listening on
+    fn refusing_addr() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_circuit_breaker_fails_fast() {
+        let (addr, seen) = mock_node(|_| r#""v""#.to_string());
+        let cooldown = Duration::from_millis(300);
+        let mut client = NubDB::builder()
+            .auto_reconnect(true)
+            .circuit_breaker(CircuitBreaker::new(2, cooldown))
+            .connect(&addr)
+            .unwrap();
+
+        client.addr = refusing_addr();
+        break_socket(&client);
+        assert!(matches!(client.get("k"), Err(NubError::Io(_))));
+        assert!(matches!(client.get("k"), Err(NubError::Io(_))));
+        assert!(matches!(client.get("k"), Err(NubError::CircuitOpen)));
+        assert!(matches!(client.reconnect(), Err(NubError::CircuitOpen)));
+
+        // One attempt after the cooldown, which opens it again on failure
+        std::thread::sleep(cooldown);
+        assert!(matches!(client.get("k"), Err(NubError::Io(_))));
+        assert!(matches!(client.get("k"), Err(NubError::CircuitOpen)));
+
+        std::thread::sleep(cooldown);
+        client.addr = addr;
+        assert_eq!(client.get("k").unwrap().as_deref(), Some("v"));
+        assert_eq!(client.circuit.failures, 0);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+}
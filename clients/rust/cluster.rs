@@ -0,0 +1,365 @@
+//! Client for a sharded cluster of NubDB nodes
+
+use std::collections::HashMap;
+
+use super::{set_command, NubDB, NubDBBuilder, NubError, Response};
+
+/// Redirects followed for one command before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// A client for a cluster whose keys are sharded across nodes by hash slot
+///
+/// Each key belongs to one of [`NubCluster::SLOT_COUNT`] slots, computed with
+/// [`NubCluster::slot`], and each slot is served by one node. The client
+/// starts out knowing only the seed nodes and learns which node owns which
+/// slot from the redirects the cluster sends back:
+///
+/// - `MOVED <slot> <addr>`: the slot now lives on `addr`. The slot map is
+///   updated and the command is sent again there.
+/// - `ASK <slot> <addr>`: the slot is being migrated, and this one command
+///   should be sent to `addr` after `ASKING`. The slot map is left alone.
+///
+/// Connections to nodes are opened on first use and kept for later commands.
+///
+/// Commands on several keys, such as [`NubCluster::mget`], only work when
+/// every key maps to the same slot, and fail with
+/// `NubError::InvalidArgument` otherwise. Keys that share a hash tag, the
+/// part between the first `{` and the next `}`, always map to the same
+/// slot: `{user:1}:name` and `{user:1}:email` can be fetched together.
+pub struct NubCluster {
+    seeds: Vec<String>,
+    builder: NubDBBuilder,
+    nodes: HashMap<String, NubDB>,
+    /// Owning node for each slot, where known
+    slots: Vec<Option<String>>,
+}
+
+/// A redirect reply from a node that does not own the slot
+#[derive(Debug, PartialEq)]
+enum Redirect {
+    Moved { slot: u16, addr: String },
+    Ask { addr: String },
+}
+
+impl NubCluster {
+    /// Number of hash slots keys are spread over
+    pub const SLOT_COUNT: u16 = 16384;
+
+    /// Connect to a cluster through any of `seeds`
+    ///
+    /// The first seed that accepts a connection is used until redirects
+    /// point elsewhere. Fails with the last error if none do.
+    pub fn connect(seeds: &[&str]) -> Result<Self, NubError> {
+        Self::with_builder(seeds, NubDB::builder())
+    }
+
+    /// Connect to a cluster, opening every node connection with `builder`
+    pub fn with_builder(seeds: &[&str], builder: NubDBBuilder) -> Result<Self, NubError> {
+        if seeds.is_empty() {
+            return Err(NubError::InvalidArgument("no seed nodes".to_string()));
+        }
+
+        let mut cluster = NubCluster {
+            seeds: seeds.iter().map(|seed| seed.to_string()).collect(),
+            builder,
+            nodes: HashMap::new(),
+            slots: vec![None; usize::from(Self::SLOT_COUNT)],
+        };
+        cluster.seed()?;
+        Ok(cluster)
+    }
+
+    /// The hash slot of `key`: CRC16 (XMODEM) of its hash tag, or of the
+    /// whole key if it has none, modulo [`NubCluster::SLOT_COUNT`]
+    pub fn slot(key: &str) -> u16 {
+        let bytes = key.as_bytes();
+        let tagged = bytes.iter().position(|&b| b == b'{').and_then(|open| {
+            let rest = &bytes[open + 1..];
+            let close = rest.iter().position(|&b| b == b'}')?;
+            // An empty tag `{}` does not count
+            (close > 0).then(|| &rest[..close])
+        });
+        crc16(tagged.unwrap_or(bytes)) % Self::SLOT_COUNT
+    }
+
+    /// The node currently believed to own `slot`, if a redirect named one
+    pub fn node_for_slot(&self, slot: u16) -> Option<&str> {
+        self.slots.get(usize::from(slot))?.as_deref()
+    }
+
+    /// SET key-value pair on the node owning `key`
+    pub fn set(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.builder.config.check_key(key)?;
+        self.builder.config.check_value(value.len())?;
+        let cmd = set_command(key, value, ttl, self.builder.config.quote_style)?;
+        Ok(self.send(key, &cmd)?.is_ok())
+    }
+
+    /// GET value by key from the node owning it
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        self.builder.config.check_key(key)?;
+        self.send(key, &format!("GET {}", key))?.into_value()
+    }
+
+    /// DELETE key on the node owning it
+    pub fn delete(&mut self, key: &str) -> Result<bool, NubError> {
+        self.builder.config.check_key(key)?;
+        Ok(self.send(key, &format!("DELETE {}", key))?.is_ok())
+    }
+
+    /// EXISTS check if key exists
+    pub fn exists(&mut self, key: &str) -> Result<bool, NubError> {
+        self.builder.config.check_key(key)?;
+        Ok(self.send(key, &format!("EXISTS {}", key))? == Response::Integer(1))
+    }
+
+    /// INCR increment counter
+    pub fn incr(&mut self, key: &str) -> Result<i64, NubError> {
+        self.incr_by(key, 1)
+    }
+
+    /// INCRBY increment counter by `amount`
+    pub fn incr_by(&mut self, key: &str, amount: i64) -> Result<i64, NubError> {
+        self.builder.config.check_key(key)?;
+        self.send(key, &format!("INCRBY {} {}", key, amount))?.into_integer()
+    }
+
+    /// EXPIRE set a key's time to live in seconds
+    pub fn expire(&mut self, key: &str, seconds: u32) -> Result<bool, NubError> {
+        self.builder.config.check_key(key)?;
+        Ok(self.send(key, &format!("EXPIRE {} {}", key, seconds))?.into_integer()? == 1)
+    }
+
+    /// MGET values for keys that all map to the same slot
+    pub fn mget(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>, NubError> {
+        let Some(first) = self.same_slot(keys.iter().copied())? else { return Ok(Vec::new()) };
+
+        let replies = self.send(first, &format!("MGET {}", keys.join(" ")))?.into_array()?;
+        if replies.len() != keys.len() {
+            return Err(NubError::UnexpectedResponse(format!(
+                "MGET returned {} values for {} keys", replies.len(), keys.len()
+            )));
+        }
+        replies.into_iter().map(Response::into_value).collect()
+    }
+
+    /// MSET key-value pairs whose keys all map to the same slot
+    pub fn mset(&mut self, pairs: &[(&str, &str)]) -> Result<bool, NubError> {
+        let Some(first) = self.same_slot(pairs.iter().map(|(key, _)| *key))? else { return Ok(true) };
+
+        let mut cmd = String::from("MSET");
+        for (key, value) in pairs {
+            self.builder.config.check_value(value.len())?;
+            cmd.push_str(&format!(" {} {}", key, self.builder.config.quote_style.quote(value)?));
+        }
+        let response = self.send(first, &cmd)?;
+        if !response.is_ok() {
+            return Err(response.into_unexpected());
+        }
+        Ok(true)
+    }
+
+    /// DEL keys that all map to the same slot, returning how many existed
+    pub fn del_many(&mut self, keys: &[&str]) -> Result<usize, NubError> {
+        let Some(first) = self.same_slot(keys.iter().copied())? else { return Ok(0) };
+        self.send(first, &format!("DEL {}", keys.join(" ")))?.into_count()
+    }
+
+    /// Validate `keys` and check they share a slot, returning the first one
+    fn same_slot<'k>(&self, mut keys: impl Iterator<Item = &'k str>) -> Result<Option<&'k str>, NubError> {
+        let Some(first) = keys.next() else { return Ok(None) };
+        self.builder.config.check_key(first)?;
+
+        let slot = Self::slot(first);
+        for key in keys {
+            self.builder.config.check_key(key)?;
+            if Self::slot(key) != slot {
+                return Err(NubError::InvalidArgument(format!(
+                    "keys {:?} and {:?} map to different slots; use a hash tag such as {{user:1}} to keep them together",
+                    first, key
+                )));
+            }
+        }
+        Ok(Some(first))
+    }
+
+    /// Send `cmd` for `key` to the node owning its slot, following redirects
+    fn send(&mut self, key: &str, cmd: &str) -> Result<Response, NubError> {
+        let slot = Self::slot(key);
+        let mut addr = match &self.slots[usize::from(slot)] {
+            Some(addr) => addr.clone(),
+            None => self.seed()?,
+        };
+        let mut asking = false;
+
+        for _ in 0..MAX_REDIRECTS {
+            let result = self.node(&addr).and_then(|node| {
+                if asking {
+                    let response = node.send_command("ASKING")?;
+                    if !response.is_ok() {
+                        return Err(response.into_unexpected());
+                    }
+                }
+                node.send_command(cmd)
+            });
+            if result.is_err() && self.nodes.get(&addr).is_some_and(NubDB::is_broken) {
+                // Reconnect on the next command instead of reusing a dead socket
+                self.nodes.remove(&addr);
+            }
+
+            match redirect(&result) {
+                Some(Redirect::Moved { slot, addr: owner }) => {
+                    if let Some(entry) = self.slots.get_mut(usize::from(slot)) {
+                        *entry = Some(owner.clone());
+                    }
+                    addr = owner;
+                    asking = false;
+                }
+                Some(Redirect::Ask { addr: target }) => {
+                    addr = target;
+                    asking = true;
+                }
+                None => return result,
+            }
+        }
+
+        Err(NubError::Protocol(format!("too many redirects for slot {}", slot)))
+    }
+
+    /// The connection to `addr`, opened if needed
+    fn node(&mut self, addr: &str) -> Result<&mut NubDB, NubError> {
+        if !self.nodes.contains_key(addr) {
+            let node = self.builder.clone().connect(addr)?;
+            self.nodes.insert(addr.to_string(), node);
+        }
+        Ok(self.nodes.get_mut(addr).expect("node was just inserted"))
+    }
+
+    /// Address of a reachable seed node, connecting to one if needed
+    fn seed(&mut self) -> Result<String, NubError> {
+        if let Some(seed) = self.seeds.iter().find(|seed| self.nodes.contains_key(*seed)) {
+            return Ok(seed.clone());
+        }
+
+        let mut last_err = None;
+        for seed in self.seeds.clone() {
+            match self.node(&seed) {
+                Ok(_) => return Ok(seed),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.expect("seeds is never empty"))
+    }
+}
+
+/// Recognize a `MOVED` or `ASK` reply, with or without a leading `-`
+fn redirect(result: &Result<Response, NubError>) -> Option<Redirect> {
+    let text = match result {
+        Ok(Response::Simple(text)) | Ok(Response::Error(text)) | Err(NubError::Protocol(text)) => text,
+        _ => return None,
+    };
+    let text = text.strip_prefix('-').unwrap_or(text);
+
+    let mut parts = text.split_whitespace();
+    let (kind, slot, addr) = (parts.next()?, parts.next()?, parts.next()?);
+    let slot = slot.parse::<u16>().ok().filter(|slot| *slot < NubCluster::SLOT_COUNT)?;
+    match kind {
+        "MOVED" => Some(Redirect::Moved { slot, addr: addr.to_string() }),
+        "ASK" => Some(Redirect::Ask { addr: addr.to_string() }),
+        _ => None,
+    }
+}
+
+/// CRC16-CCITT (XMODEM): polynomial 0x1021, initial value 0
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::mock_node;
+
+    #[test]
+    fn test_slot() {
+        // Reference values from the Redis cluster specification
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(NubCluster::slot("foo"), 12182);
+        assert_eq!(NubCluster::slot("bar"), 5061);
+        assert_eq!(NubCluster::slot("{user1000}.following"), NubCluster::slot("{user1000}.followers"));
+        assert_eq!(NubCluster::slot("{user1000}.following"), NubCluster::slot("user1000"));
+        assert_eq!(NubCluster::slot("foo{}{bar}"), crc16(b"foo{}{bar}") % NubCluster::SLOT_COUNT);
+    }
+
+    #[test]
+    fn test_redirect_parsing() {
+        assert_eq!(
+            redirect(&Ok(Response::Simple("-MOVED 3999 127.0.0.1:6381".to_string()))),
+            Some(Redirect::Moved { slot: 3999, addr: "127.0.0.1:6381".to_string() })
+        );
+        assert_eq!(
+            redirect(&Err(NubError::Protocol("ASK 42 10.0.0.2:6379".to_string()))),
+            Some(Redirect::Ask { addr: "10.0.0.2:6379".to_string() })
+        );
+        assert_eq!(redirect(&Ok(Response::Simple("OK".to_string()))), None);
+        assert_eq!(redirect(&Ok(Response::Simple("MOVED 99999 a:1".to_string()))), None);
+    }
+
+    #[test]
+    fn test_moved_updates_slot_map() {
+        let (owner, owner_seen) = mock_node(|cmd| match cmd {
+            "GET foo" => "\"bar\"".to_string(),
+            _ => "OK".to_string(),
+        });
+        let slot = NubCluster::slot("foo");
+        let moved = format!("-MOVED {} {}", slot, owner);
+        let (seed, seed_seen) = mock_node(move |_| moved.clone());
+
+        let mut cluster = NubCluster::connect(&[&seed]).unwrap();
+        assert_eq!(cluster.get("foo").unwrap().as_deref(), Some("bar"));
+        assert_eq!(cluster.node_for_slot(slot), Some(owner.as_str()));
+
+        // The second command goes straight to the owner
+        assert_eq!(cluster.get("foo").unwrap().as_deref(), Some("bar"));
+        assert_eq!(seed_seen.lock().unwrap().len(), 1);
+        assert_eq!(owner_seen.lock().unwrap().as_slice(), ["GET foo", "GET foo"]);
+    }
+
+    #[test]
+    fn test_ask_redirects_once() {
+        let (target, target_seen) = mock_node(|cmd| match cmd {
+            "ASKING" => "OK".to_string(),
+            "GET foo" => "\"migrating\"".to_string(),
+            _ => "ERROR: unexpected".to_string(),
+        });
+        let ask = format!("-ASK {} {}", NubCluster::slot("foo"), target);
+        let (seed, seed_seen) = mock_node(move |_| ask.clone());
+
+        let mut cluster = NubCluster::connect(&[&seed]).unwrap();
+        assert_eq!(cluster.get("foo").unwrap().as_deref(), Some("migrating"));
+        assert_eq!(cluster.node_for_slot(NubCluster::slot("foo")), None);
+        assert_eq!(target_seen.lock().unwrap().as_slice(), ["ASKING", "GET foo"]);
+
+        // ASK does not move the slot, so the seed is asked again
+        cluster.get("foo").unwrap();
+        assert_eq!(seed_seen.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_cross_slot_commands_are_rejected() {
+        let (seed, seed_seen) = mock_node(|_| "OK".to_string());
+        let mut cluster = NubCluster::connect(&[&seed]).unwrap();
+
+        assert!(matches!(cluster.mget(&["foo", "bar"]), Err(NubError::InvalidArgument(_))));
+        assert!(matches!(cluster.mset(&[("foo", "1"), ("bar", "2")]), Err(NubError::InvalidArgument(_))));
+        assert!(cluster.mset(&[("{user:1}:name", "Ada"), ("{user:1}:email", "ada@example.com")]).unwrap());
+        assert_eq!(seed_seen.lock().unwrap().len(), 1);
+    }
+}
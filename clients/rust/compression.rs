@@ -0,0 +1,201 @@
+//! Transparent compression of large values, see the crate docs for the format
+
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use super::{NubDB, NubError};
+
+/// Marks a stored value as compressed: the byte `0x1F` followed by `gz:`
+pub(crate) const PREFIX: &str = "\u{1f}gz:";
+/// Marks an uncompressed value that would otherwise look compressed
+pub(crate) const RAW_PREFIX: &str = "\u{1f}raw:";
+
+/// Settings for compressing values, see [`NubDBBuilder::compression`]
+///
+/// [`NubDBBuilder::compression`]: super::NubDBBuilder::compression
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    threshold: usize,
+    level: u32,
+    min_ratio: f64,
+}
+
+impl Default for CompressionConfig {
+    /// Compress values of 1 KiB or more at gzip level 6, whenever that
+    /// makes them smaller
+    fn default() -> Self {
+        CompressionConfig { threshold: 1024, level: 6, min_ratio: 1.0 }
+    }
+}
+
+impl CompressionConfig {
+    /// Compress values of at least `threshold` bytes
+    pub fn new(threshold: usize) -> Self {
+        CompressionConfig { threshold, ..Self::default() }
+    }
+
+    /// Gzip level from 0 (fastest) to 9 (smallest), 6 by default
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+
+    /// Only store a value compressed if that makes it at least `ratio` times
+    /// smaller, 1.0 by default
+    ///
+    /// The size compared is the stored form, prefix and base64 included.
+    /// Data that is already compressed, such as images, barely shrinks; a
+    /// ratio such as 1.2 stores it as-is instead. Ratios below 1.0 count as
+    /// 1.0, as a value is never stored compressed when that makes it larger.
+    pub fn min_ratio(mut self, ratio: f64) -> Self {
+        self.min_ratio = ratio.max(1.0);
+        self
+    }
+
+    /// The form of `value` to store
+    ///
+    /// Values under the threshold, and larger ones that shrink by less than
+    /// the minimum ratio, are stored as [`raw`] makes them.
+    pub(crate) fn encode<'a>(&self, value: &'a str) -> Result<Cow<'a, str>, NubError> {
+        if value.len() < self.threshold {
+            return Ok(raw(value));
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::new(self.level));
+        encoder.write_all(value.as_bytes())?;
+        let compressed = encoder.finish()?;
+
+        let mut encoded = String::from(PREFIX);
+        STANDARD.encode_string(compressed, &mut encoded);
+        if (value.len() as f64) <= encoded.len() as f64 * self.min_ratio {
+            return Ok(raw(value));
+        }
+        Ok(Cow::Owned(encoded))
+    }
+}
+
+/// The form of `value` to store uncompressed
+///
+/// Only a value that starts with [`PREFIX`] or [`RAW_PREFIX`] needs
+/// changing, by putting [`RAW_PREFIX`] in front, so that reading it back
+/// cannot mistake it for a marked value.
+pub(crate) fn raw(value: &str) -> Cow<'_, str> {
+    if value.starts_with(PREFIX) || value.starts_with(RAW_PREFIX) {
+        return Cow::Owned(format!("{}{}", RAW_PREFIX, value));
+    }
+    Cow::Borrowed(value)
+}
+
+/// Undo [`CompressionConfig::encode`]; values without a prefix pass through
+pub(crate) fn decode(value: String) -> Result<String, NubError> {
+    if let Some(raw) = value.strip_prefix(RAW_PREFIX) {
+        return Ok(raw.to_string());
+    }
+    let Some(encoded) = value.strip_prefix(PREFIX) else { return Ok(value) };
+
+    let compressed = STANDARD.decode(encoded)
+        .map_err(|e| NubError::Parse(format!("invalid compressed value: {}", e)))?;
+    let mut decompressed = String::new();
+    GzDecoder::new(compressed.as_slice())
+        .read_to_string(&mut decompressed)
+        .map_err(|e| NubError::Parse(format!("invalid compressed value: {}", e)))?;
+    Ok(decompressed)
+}
+
+impl NubDB {
+    /// SET a value uncompressed, whatever the compression settings
+    ///
+    /// For values gzip cannot shrink, such as images or already compressed
+    /// data, where trying only costs CPU. They read back with
+    /// [`NubDB::get`] like any other value.
+    pub fn set_raw(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.config.check_key(key)?;
+        self.config.check_value(value.len())?;
+        let value = match self.config.compression {
+            Some(_) => raw(value),
+            None => Cow::Borrowed(value),
+        };
+        self.set_encoded(key, &value, ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NubDB;
+
+    #[test]
+    fn test_encode_decode() {
+        let config = CompressionConfig::new(64);
+
+        let small = "short";
+        assert!(matches!(config.encode(small).unwrap(), Cow::Borrowed(_)));
+
+        let large = "{\"name\":\"value\"}".repeat(100);
+        let encoded = config.encode(&large).unwrap();
+        assert!(encoded.starts_with(PREFIX));
+        assert!(encoded.len() < large.len() / 5);
+        assert_eq!(decode(encoded.into_owned()).unwrap(), large);
+
+        // Values that look marked are marked as raw, so they round-trip
+        for marked in [format!("{}not really", PREFIX), format!("{}nor this", RAW_PREFIX)] {
+            let encoded = config.encode(&marked).unwrap();
+            assert_eq!(encoded, format!("{}{}", RAW_PREFIX, marked));
+            assert_eq!(decode(encoded.into_owned()).unwrap(), marked);
+        }
+
+        // Random letters shrink by less than half, so a ratio keeps them uncompressed
+        let mut state = 1u64;
+        let noisy: String = (0..4096).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            char::from(b'a' + (state >> 60) as u8)
+        }).collect();
+        assert!(config.encode(&noisy).unwrap().starts_with(PREFIX));
+        assert!(matches!(config.clone().min_ratio(2.0).encode(&noisy).unwrap(), Cow::Borrowed(_)));
+        assert!(config.min_ratio(2.0).encode(&large).unwrap().starts_with(PREFIX));
+
+        assert!(matches!(decode(format!("{}!!!", PREFIX)), Err(NubError::Parse(_))));
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_compressed_round_trip() {
+        let mut client = NubDB::builder()
+            .compression(CompressionConfig::new(64))
+            .connect("localhost:6379")
+            .unwrap();
+        let mut plain = NubDB::connect("localhost:6379").unwrap();
+
+        let large = "line of verbose text\n".repeat(100);
+        client.set("compression:large", &large, None).unwrap();
+        client.set("compression:small", "small", None).unwrap();
+        assert_eq!(client.get("compression:large").unwrap().as_deref(), Some(large.as_str()));
+        assert_eq!(
+            client.mget(&["compression:large", "compression:small"]).unwrap(),
+            vec![Some(large.clone()), Some("small".to_string())]
+        );
+
+        // Stored compressed, readable by clients that know the format
+        let stored = plain.get("compression:large").unwrap().unwrap();
+        assert!(stored.starts_with(PREFIX));
+        assert_eq!(decode(stored).unwrap(), large);
+        assert_eq!(plain.get("compression:small").unwrap().as_deref(), Some("small"));
+
+        // Raw values are stored as they are, next to compressed ones
+        client.set_raw("compression:raw", &large, None).unwrap();
+        assert_eq!(plain.get("compression:raw").unwrap().as_deref(), Some(large.as_str()));
+        assert_eq!(client.get("compression:raw").unwrap().as_deref(), Some(large.as_str()));
+        let marked = format!("{}raw", PREFIX);
+        client.set_raw("compression:raw", &marked, None).unwrap();
+        assert_eq!(client.get("compression:raw").unwrap(), Some(marked));
+
+        client.close().unwrap();
+        plain.close().unwrap();
+    }
+}
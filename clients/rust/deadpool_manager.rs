@@ -0,0 +1,82 @@
+//! `deadpool` async connection pool integration
+
+use deadpool::managed::{Manager, Metrics, RecycleError, RecycleResult};
+
+use super::{AsyncNubDB, NubError};
+
+/// A [`deadpool::managed::Manager`] for [`AsyncNubDB`] connections
+///
+/// ```no_run
+/// # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+/// # use deadpool::managed::Pool;
+/// # use nubdb::NubManager;
+/// let pool: Pool<NubManager> = Pool::builder(NubManager::new("localhost:6379")).build()?;
+/// let mut conn = pool.get().await?;
+/// conn.set("key", "value", None).await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// A connection is pinged before it is handed out again and replaced if the
+/// ping fails. A connection whose command future was dropped mid-command is
+/// out of sync with the server and is replaced without being pinged.
+///
+/// A pooled connection is only returned when its [`Object`] is dropped, so a
+/// connection borrowed for a whole transaction stays checked out until the
+/// transaction is done. Operations that take the connection by value, such
+/// as [`NubDB::subscribe`](super::NubDB::subscribe) on the blocking client,
+/// must first detach it from the pool with [`Object::take`]; a detached
+/// connection never goes back, and the pool opens a new one in its place.
+///
+/// [`Object`]: deadpool::managed::Object
+/// [`Object::take`]: deadpool::managed::Object::take
+pub struct NubManager {
+    addr: String,
+}
+
+impl NubManager {
+    /// Open pooled connections to `addr`
+    pub fn new(addr: &str) -> Self {
+        NubManager { addr: addr.to_string() }
+    }
+}
+
+impl Manager for NubManager {
+    type Type = AsyncNubDB;
+    type Error = NubError;
+
+    async fn create(&self) -> Result<AsyncNubDB, NubError> {
+        AsyncNubDB::connect(self.addr.as_str()).await
+    }
+
+    async fn recycle(&self, conn: &mut AsyncNubDB, _: &Metrics) -> RecycleResult<NubError> {
+        if conn.is_desynchronized() {
+            return Err(RecycleError::message("connection is out of sync with the server"));
+        }
+        conn.ping().await.map(|_| ()).map_err(RecycleError::Backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use deadpool::managed::Pool;
+
+    #[tokio::test]
+    #[ignore = "needs a running server"]
+    async fn test_deadpool_reuses_connections() {
+        let pool: Pool<NubManager> = Pool::builder(NubManager::new("localhost:6379"))
+            .max_size(1)
+            .build()
+            .unwrap();
+
+        {
+            let mut conn = pool.get().await.unwrap();
+            assert!(conn.set("deadpool:key", "value", None).await.unwrap());
+        }
+
+        let mut conn = pool.get().await.unwrap();
+        assert_eq!(conn.get("deadpool:key").await.unwrap().as_deref(), Some("value"));
+        assert_eq!(pool.status().size, 1);
+    }
+}
@@ -0,0 +1,211 @@
+//! Exporting the keyspace to a file and importing it again
+
+use std::io::{self, Read, Write};
+
+use super::{NubDB, NubError};
+
+/// Start of every export, followed by the format version
+const MAGIC: &[u8; 4] = b"NUBX";
+/// The format [`NubDB::export`] writes. [`NubDB::import`] reads this and
+/// every earlier version.
+const VERSION: u8 = 1;
+
+const RECORD: u8 = 1;
+const END: u8 = 0;
+
+impl NubDB {
+    /// Write every key in the current database to `writer`, returning how
+    /// many were written
+    ///
+    /// Keys are found with [`NubDB::scan`] and each one is saved with
+    /// [`NubDB::dump`] together with its remaining TTL, so the server is
+    /// never walked in one go. Keys created or deleted during the export may
+    /// or may not be included. The output is binary:
+    ///
+    /// ```text
+    /// "NUBX" version:u8
+    /// repeated: 0x01 key_len:u32 key ttl_ms:u64 blob_len:u32 blob
+    /// 0x00 count:u64
+    /// ```
+    ///
+    /// Integers are big-endian. `ttl_ms` is the time left when the key was
+    /// exported, `0` for no expiry, and the blob is exactly what DUMP
+    /// returned. The trailing count is the number of records, so a
+    /// truncated file is detected on import. Version 1 is the only one so
+    /// far; later versions of the client keep importing it.
+    pub fn export(&mut self, writer: &mut dyn Write) -> Result<usize, NubError> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+
+        let mut scan = self.scan(None);
+        let mut count = 0u64;
+        while !scan.finished {
+            scan.fetch_page()?;
+            let keys: Vec<String> = scan.page.by_ref().collect();
+            for key in keys {
+                // Dump first: a key that then turns out to be gone is skipped
+                let Some(blob) = scan.client.dump(&key)? else { continue };
+                let ttl_ms = match scan.client.ttl_duration(&key) {
+                    // About to expire, but 0 would mean it never does
+                    Ok(ttl) => ttl.map_or(0, |ttl| (ttl.as_millis() as u64).max(1)),
+                    Err(NubError::KeyNotFound(_)) => continue,
+                    Err(e) => return Err(e),
+                };
+
+                writer.write_all(&[RECORD])?;
+                write_chunk(writer, key.as_bytes())?;
+                writer.write_all(&ttl_ms.to_be_bytes())?;
+                write_chunk(writer, &blob)?;
+                count += 1;
+            }
+        }
+
+        writer.write_all(&[END])?;
+        writer.write_all(&count.to_be_bytes())?;
+        writer.flush()?;
+        Ok(count as usize)
+    }
+
+    /// Restore every key in an export made by [`NubDB::export`], returning
+    /// how many were restored
+    ///
+    /// Each key is restored with [`NubDB::restore`] and the TTL it had left
+    /// when exported, counted from now. Unless `replace` is set, a key that
+    /// already exists stops the import with the server's error, leaving the
+    /// keys before it restored. A file that is not an export, or that ends
+    /// early, is `NubError::Parse`.
+    pub fn import(&mut self, reader: &mut dyn Read, replace: bool) -> Result<usize, NubError> {
+        let mut header = [0u8; 5];
+        read_exact(reader, &mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(NubError::Parse("not a NubDB export".to_string()));
+        }
+        if header[4] == 0 || header[4] > VERSION {
+            return Err(NubError::Parse(format!("unsupported export version {}", header[4])));
+        }
+
+        let mut count = 0u64;
+        loop {
+            let mut tag = [0u8; 1];
+            read_exact(reader, &mut tag)?;
+            match tag[0] {
+                RECORD => {}
+                END => break,
+                other => return Err(NubError::Parse(format!("unknown export record type {}", other))),
+            }
+
+            let key = String::from_utf8(read_chunk(reader)?)
+                .map_err(|e| NubError::Parse(format!("exported key is not UTF-8: {}", e)))?;
+            let ttl_ms = u64::from_be_bytes(read_array(reader)?);
+            let blob = read_chunk(reader)?;
+            self.restore_ms(&key, ttl_ms, &blob, replace)?;
+            count += 1;
+        }
+
+        let expected = u64::from_be_bytes(read_array(reader)?);
+        if expected != count {
+            return Err(NubError::Parse(format!("export holds {} keys but {} were read", expected, count)));
+        }
+        Ok(count as usize)
+    }
+}
+
+/// Write a `u32` length and then `bytes`
+fn write_chunk(writer: &mut dyn Write, bytes: &[u8]) -> Result<(), NubError> {
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| NubError::InvalidArgument(format!("{} bytes is too large to export", bytes.len())))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// Read a chunk written by [`write_chunk`]
+fn read_chunk(reader: &mut dyn Read) -> Result<Vec<u8>, NubError> {
+    let len = u32::from_be_bytes(read_array(reader)?) as usize;
+    let mut bytes = Vec::new();
+    reader.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(truncated());
+    }
+    Ok(bytes)
+}
+
+fn read_array<const N: usize>(reader: &mut dyn Read) -> Result<[u8; N], NubError> {
+    let mut bytes = [0u8; N];
+    read_exact(reader, &mut bytes)?;
+    Ok(bytes)
+}
+
+/// `read_exact`, reporting an early end as a truncated export
+fn read_exact(reader: &mut dyn Read, buf: &mut [u8]) -> Result<(), NubError> {
+    match reader.read_exact(buf) {
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(truncated()),
+        result => Ok(result?),
+    }
+}
+
+fn truncated() -> NubError {
+    NubError::Parse("export ends early".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_export_and_import() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        client.select(13).unwrap();
+        client.flush_db().unwrap();
+        client.set("export:text", "line one\nline two", None).unwrap();
+        client.set("export:expiring", "soon", Some(60)).unwrap();
+        client.rpush("export:list", "a").unwrap();
+        client.rpush("export:list", "b").unwrap();
+
+        let mut file = Vec::new();
+        assert_eq!(client.export(&mut file).unwrap(), 3);
+        assert_eq!(&file[..5], b"NUBX\x01");
+
+        client.flush_db().unwrap();
+        assert_eq!(client.import(&mut &file[..], false).unwrap(), 3);
+        assert_eq!(client.get("export:text").unwrap().as_deref(), Some("line one\nline two"));
+        assert_eq!(client.lrange("export:list", 0, -1).unwrap(), vec!["a", "b"]);
+        assert!(client.ttl("export:expiring").unwrap().is_some_and(|ttl| ttl > 0 && ttl <= 60));
+        assert_eq!(client.ttl("export:text").unwrap(), None);
+
+        // Existing keys are only overwritten with `replace`
+        assert!(matches!(client.import(&mut &file[..], false), Err(NubError::Protocol(_))));
+        assert_eq!(client.import(&mut &file[..], true).unwrap(), 3);
+
+        client.flush_db().unwrap();
+        let mut empty = Vec::new();
+        assert_eq!(client.export(&mut empty).unwrap(), 0);
+        assert_eq!(client.import(&mut &empty[..], false).unwrap(), 0);
+        client.close().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_import_rejects_bad_input() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        client.select(13).unwrap();
+        let mut record = b"NUBX\x01\x01".to_vec();
+        record.extend(3u32.to_be_bytes());
+        record.extend(b"key");
+
+        for (input, what) in [
+            (&b"REDIS0011"[..], "wrong magic"),
+            (&b"NUBX\x09\x00"[..], "future version"),
+            (&b"NUBX\x01\x07"[..], "unknown record"),
+            (&b"NUBX\x01"[..], "no end marker"),
+            (&record[..], "truncated record"),
+            (&b"NUBX\x01\x00\x00\x00\x00\x00\x00\x00\x00\x02"[..], "wrong count"),
+        ] {
+            assert!(matches!(client.import(&mut &input[..], false), Err(NubError::Parse(_))), "{}", what);
+        }
+
+        assert!(client.ping().is_ok());
+        client.close().unwrap();
+    }
+}
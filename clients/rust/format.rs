@@ -0,0 +1,184 @@
+//! Storing serde values in a choice of encodings, see the crate docs for the
+//! format tag
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{NubDB, NubError};
+
+/// The encoding [`NubDB::set_value`] stores values in
+///
+/// Set per client with [`NubDBBuilder::value_format`], or per call with
+/// [`NubDB::set_value_as`] and [`NubDB::get_value_as`]. `MessagePack` needs
+/// the `msgpack` feature and `Bincode` the `bincode` feature.
+///
+/// [`NubDBBuilder::value_format`]: super::NubDBBuilder::value_format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// JSON, readable by anything and the largest of the three
+    #[default]
+    Json,
+    /// MessagePack, a compact binary form of the same data model as JSON
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    /// bincode 1, the most compact, but only readable by a program with the
+    /// same Rust types
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+impl Format {
+    /// The bytes stored in front of a value in this format: `0x1F`, two
+    /// letters and `:`
+    fn tag(self) -> &'static [u8] {
+        match self {
+            Format::Json => b"\x1fjs:",
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => b"\x1fmp:",
+            #[cfg(feature = "bincode")]
+            Format::Bincode => b"\x1fbc:",
+        }
+    }
+
+    /// The format whose tag starts `bytes`, if any enabled format's does
+    fn of(bytes: &[u8]) -> Option<Format> {
+        let formats = [
+            Format::Json,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack,
+            #[cfg(feature = "bincode")]
+            Format::Bincode,
+        ];
+        formats.into_iter().find(|format| bytes.starts_with(format.tag()))
+    }
+
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, NubError> {
+        let mut bytes = self.tag().to_vec();
+        let written = match self {
+            Format::Json => serde_json::to_writer(&mut bytes, value).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::encode::write_named(&mut bytes, value).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => bincode::serialize_into(&mut bytes, value).map_err(|e| e.to_string()),
+        };
+        written.map_err(NubError::Serialization)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, NubError> {
+        let decoded = match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            #[cfg(feature = "bincode")]
+            Format::Bincode => bincode::deserialize(bytes).map_err(|e| e.to_string()),
+        };
+        decoded.map_err(NubError::Serialization)
+    }
+}
+
+impl NubDB {
+    /// SET a value serialized in the client's [`Format`], JSON by default
+    ///
+    /// The value is stored tagged with its format, as binary, so it must be
+    /// read back with [`NubDB::get_value`] rather than [`NubDB::get`].
+    pub fn set_value<T: Serialize>(&mut self, key: &str, value: &T, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.set_value_as(key, value, ttl, self.config.value_format)
+    }
+
+    /// SET a value serialized in `format`, whatever the client's default
+    pub fn set_value_as<T: Serialize>(
+        &mut self,
+        key: &str,
+        value: &T,
+        ttl: Option<u32>,
+        format: Format,
+    ) -> Result<bool, NubError> {
+        let bytes = format.encode(value)?;
+        self.set_bytes(key, &bytes, ttl)
+    }
+
+    /// GET a value stored with [`NubDB::set_value`] in the client's
+    /// [`Format`] and deserialize it
+    ///
+    /// A missing key is `Ok(None)`. A value stored in another format, or
+    /// that does not deserialize as `T`, is `NubError::Serialization`.
+    pub fn get_value<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>, NubError> {
+        self.get_value_as(key, self.config.value_format)
+    }
+
+    /// GET a value stored in `format` and deserialize it
+    ///
+    /// When `format` is [`Format::Json`], a value with no tag at all, such
+    /// as one written with [`NubDB::set_json`], is read as JSON too.
+    pub fn get_value_as<T: DeserializeOwned>(&mut self, key: &str, format: Format) -> Result<Option<T>, NubError> {
+        let Some(bytes) = self.get_bytes(key)? else { return Ok(None) };
+        match Format::of(&bytes) {
+            Some(stored) if stored == format => format.decode(&bytes[format.tag().len()..]).map(Some),
+            Some(stored) => Err(NubError::Serialization(format!(
+                "{:?} is stored as {:?}, not {:?}", key, stored, format
+            ))),
+            None if format == Format::Json && !bytes.starts_with(b"\x1f") => format.decode(&bytes).map(Some),
+            None => Err(NubError::Serialization(format!("{:?} was not stored with set_value", key))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+    struct Order {
+        id: u64,
+        items: Vec<(String, u32)>,
+        note: Option<String>,
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        let order = Order { id: 7, items: vec![("apple\n".to_string(), 3)], note: None };
+        let formats = [
+            Format::Json,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack,
+            #[cfg(feature = "bincode")]
+            Format::Bincode,
+        ];
+        for format in formats {
+            let bytes = format.encode(&order).unwrap();
+            assert_eq!(Format::of(&bytes), Some(format));
+            assert_eq!(format.decode::<Order>(&bytes[format.tag().len()..]).unwrap(), order);
+        }
+        assert_eq!(Format::of(b"{\"id\":7}"), None);
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_set_and_get_value() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        let order = Order { id: 1, items: vec![("pear".to_string(), 2)], note: Some("gift".to_string()) };
+
+        assert!(client.set_value("format:json", &order, None).unwrap());
+        assert_eq!(client.get_value::<Order>("format:json").unwrap(), Some(order));
+        client.delete("format:missing").unwrap();
+        assert_eq!(client.get_value::<Order>("format:missing").unwrap(), None);
+
+        // Untagged JSON from set_json reads back
+        client.set_json("format:plain", &vec![1, 2, 3], None).unwrap();
+        assert_eq!(client.get_value::<Vec<u8>>("format:plain").unwrap(), Some(vec![1, 2, 3]));
+        client.close().unwrap();
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_format_mismatch_is_an_error() {
+        let mut client = NubDB::builder().value_format(Format::MessagePack).connect("localhost:6379").unwrap();
+        client.set_value("format:msgpack", &vec!["a", "b"], None).unwrap();
+        assert_eq!(client.get_value::<Vec<String>>("format:msgpack").unwrap(), Some(vec!["a".to_string(), "b".to_string()]));
+        let read = client.get_value_as::<Vec<String>>("format:msgpack", Format::Json);
+        assert!(matches!(read, Err(NubError::Serialization(_))));
+        client.close().unwrap();
+    }
+}
@@ -0,0 +1,196 @@
+//! Parsed INFO replies
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::{NubDB, NubError, Response};
+
+/// Server statistics returned by [`NubDB::info`](super::NubDB::info)
+///
+/// The reply is a block of `key:value` lines, optionally grouped under
+/// `# Section` headings. The commonly used fields are parsed out; every
+/// field, including ones this client does not know about, is also kept in
+/// [`ServerInfo::fields`]. A field the server did not send is `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerInfo {
+    /// Seconds since the server started (`uptime_in_seconds`)
+    pub uptime_seconds: Option<u64>,
+    /// Number of open client connections (`connected_clients`)
+    pub connected_clients: Option<u64>,
+    /// Memory used by the server in bytes (`used_memory`)
+    pub used_memory: Option<u64>,
+    /// Number of keys across all databases, from `total_keys` or the sum of
+    /// the per-database `dbN:keys=...` lines
+    pub total_keys: Option<u64>,
+    /// Every `key:value` pair in the reply
+    pub fields: HashMap<String, String>,
+}
+
+impl ServerInfo {
+    /// Parse the text of an INFO reply
+    pub fn parse(text: &str) -> Self {
+        let fields: HashMap<String, String> = text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+            .collect();
+
+        let number = |key: &str| fields.get(key).and_then(|v| v.parse::<u64>().ok());
+
+        let total_keys = number("total_keys").or_else(|| {
+            let mut per_db = fields.iter()
+                .filter(|(key, _)| is_db_field(key))
+                .filter_map(|(_, value)| db_key_count(value))
+                .peekable();
+            per_db.peek()?;
+            Some(per_db.sum())
+        });
+
+        ServerInfo {
+            uptime_seconds: number("uptime_in_seconds"),
+            connected_clients: number("connected_clients"),
+            used_memory: number("used_memory"),
+            total_keys,
+            fields,
+        }
+    }
+
+    /// The raw value of any field
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(String::as_str)
+    }
+}
+
+/// Readiness reported by [`NubDB::health_check`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Health {
+    /// Whether the server answered and is ready to serve its data
+    pub ok: bool,
+    /// Round-trip time of the PING
+    pub latency: Duration,
+    /// The server's `role` INFO field, such as `master` or `slave`
+    pub role: Option<String>,
+    /// Why the server is not ready, when `ok` is `false`
+    pub reason: Option<String>,
+}
+
+impl NubDB {
+    /// PING the server and read its role and loading state from INFO
+    ///
+    /// Meant for readiness probes. A server still loading its dataset
+    /// reports `ok: false` with a reason, whether it says so by answering
+    /// PING with a `LOADING` error or through the `loading` INFO field. A
+    /// server without INFO is judged on the PING alone. Network failures,
+    /// and any other error reply to PING, are returned as errors.
+    pub fn health_check(&mut self) -> Result<Health, NubError> {
+        let start = Instant::now();
+        let pong = self.send_command("PING");
+        let latency = start.elapsed();
+        match pong {
+            Ok(Response::Simple(reply)) if reply == "PONG" => {}
+            // Only PING is read as bare `LOADING`, as it never returns a value
+            Ok(Response::Simple(msg)) | Err(NubError::Protocol(msg)) if msg.starts_with("LOADING ") => {
+                return Ok(Health { ok: false, latency, role: None, reason: Some(msg) });
+            }
+            Ok(response) => return Err(response.into_unexpected()),
+            Err(e) => return Err(e),
+        }
+
+        let info = match self.info() {
+            Ok(info) => info,
+            Err(NubError::Protocol(_)) => ServerInfo::default(),
+            Err(e) => return Err(e),
+        };
+        let role = info.get("role").map(str::to_string);
+        if info.get("loading") == Some("1") {
+            let reason = Some("server is loading its dataset".to_string());
+            return Ok(Health { ok: false, latency, role, reason });
+        }
+        Ok(Health { ok: true, latency, role, reason: None })
+    }
+}
+
+/// Whether `key` is a keyspace line such as `db0`
+fn is_db_field(key: &str) -> bool {
+    key.strip_prefix("db")
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// The `keys=` count in a keyspace value such as `keys=3,expires=1`
+fn db_key_count(value: &str) -> Option<u64> {
+    value.split(',')
+        .find_map(|part| part.strip_prefix("keys="))
+        .and_then(|n| n.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_info() {
+        let text = "# Server\r\nuptime_in_seconds:120\r\nversion:1.0\r\n\r\n\
+                    # Clients\r\nconnected_clients:3\r\n\r\n\
+                    # Memory\r\nused_memory:1048576\r\n\r\n\
+                    # Keyspace\r\ndb0:keys=5,expires=1\r\ndb2:keys=2,expires=0\r\n";
+        let info = ServerInfo::parse(text);
+
+        assert_eq!(info.uptime_seconds, Some(120));
+        assert_eq!(info.connected_clients, Some(3));
+        assert_eq!(info.used_memory, Some(1048576));
+        assert_eq!(info.total_keys, Some(7));
+        assert_eq!(info.get("version"), Some("1.0"));
+        assert_eq!(info.get("missing"), None);
+    }
+
+    #[test]
+    fn test_parse_sparse_info() {
+        let info = ServerInfo::parse("total_keys:9\nused_memory:lots\n");
+
+        assert_eq!(info.total_keys, Some(9));
+        assert_eq!(info.used_memory, None);
+        assert_eq!(info.uptime_seconds, None);
+        assert_eq!(ServerInfo::parse(""), ServerInfo::default());
+    }
+
+    #[test]
+    fn test_health_check() {
+        let (addr, _) = crate::tests::mock_node(|cmd| match cmd {
+            "PING" => "PONG".to_string(),
+            _ => "$22\nrole:master\r\nloading:0".to_string(),
+        });
+        let health = NubDB::connect(&addr).unwrap().health_check().unwrap();
+        assert!(health.ok);
+        assert_eq!(health.role.as_deref(), Some("master"));
+        assert_eq!(health.reason, None);
+
+        let (addr, _) = crate::tests::mock_node(|cmd| match cmd {
+            "PING" => "PONG".to_string(),
+            _ => "$21\nrole:slave\r\nloading:1".to_string(),
+        });
+        let health = NubDB::connect(&addr).unwrap().health_check().unwrap();
+        assert!(!health.ok);
+        assert!(health.reason.is_some());
+
+        let (addr, _) = crate::tests::mock_node(|_| "LOADING dataset is being loaded".to_string());
+        let health = NubDB::connect(&addr).unwrap().health_check().unwrap();
+        assert_eq!(health.reason.as_deref(), Some("LOADING dataset is being loaded"));
+        let (addr, _) = crate::tests::mock_node(|_| "-LOADING dataset is being loaded".to_string());
+        let health = NubDB::connect(&addr).unwrap().health_check().unwrap();
+        assert_eq!(health.reason.as_deref(), Some("LOADING dataset is being loaded"));
+
+        // Anywhere else a bare `LOADING` is just a value
+        let (addr, _) = crate::tests::mock_node(|_| "LOADING-bay".to_string());
+        assert_eq!(NubDB::connect(&addr).unwrap().get("dock").unwrap().as_deref(), Some("LOADING-bay"));
+
+        // No INFO, so PING alone decides
+        let (addr, _) = crate::tests::mock_node(|cmd| match cmd {
+            "PING" => "PONG".to_string(),
+            _ => "ERROR: Unknown command".to_string(),
+        });
+        let health = NubDB::connect(&addr).unwrap().health_check().unwrap();
+        assert!(health.ok);
+        assert_eq!(health.role, None);
+    }
+}
@@ -0,0 +1,114 @@
+//! Running one operation on several independent nodes at once
+
+use std::thread;
+
+use super::{NubDB, NubDBBuilder, NubError};
+
+/// Connections to several independent NubDB servers, queried together
+///
+/// Unlike [`NubCluster`](super::NubCluster), the nodes share nothing and
+/// keys are not routed: [`map_reduce`](Self::map_reduce) runs the same
+/// operation against every node, each on its own thread, and combines what
+/// they return. This suits data the application has split across servers
+/// itself, such as a counter kept on each of them:
+///
+/// ```no_run
+/// # use nubdb::{MultiNode, NubError};
+/// # fn main() -> Result<(), NubError> {
+/// let mut nodes = MultiNode::connect(&["shard-1:6379", "shard-2:6379", "shard-3:6379"])?;
+/// let visits = nodes.map_reduce(
+///     |node| Ok(node.get_typed::<i64>("visits")?.unwrap_or(0)),
+///     |counts| counts.into_iter().sum(),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct MultiNode {
+    nodes: Vec<NubDB>,
+}
+
+impl MultiNode {
+    /// Connect to every node in `addrs`
+    ///
+    /// Fails with the first error if any node cannot be reached, since a
+    /// result combined from only some of them would be wrong.
+    pub fn connect(addrs: &[&str]) -> Result<Self, NubError> {
+        Self::with_builder(addrs, NubDB::builder())
+    }
+
+    /// Connect to every node, opening each connection with `builder`
+    pub fn with_builder(addrs: &[&str], builder: NubDBBuilder) -> Result<Self, NubError> {
+        if addrs.is_empty() {
+            return Err(NubError::InvalidArgument("no nodes".to_string()));
+        }
+        let nodes = addrs
+            .iter()
+            .map(|addr| builder.clone().connect(addr))
+            .collect::<Result<_, _>>()?;
+        Ok(MultiNode { nodes })
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Always false, as connecting needs at least one node
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// The connection to the node at `index`, in the order the addresses
+    /// were given
+    pub fn node(&mut self, index: usize) -> Option<&mut NubDB> {
+        self.nodes.get_mut(index)
+    }
+
+    /// Run `op` against every node in parallel and combine the results with
+    /// `reduce`
+    ///
+    /// `reduce` is given one result per node, in the order the addresses
+    /// were given. It only runs once every node has answered: if `op` fails
+    /// on any node, the error from the first such node is returned instead.
+    pub fn map_reduce<T, F, R>(&mut self, op: F, reduce: R) -> Result<T, NubError>
+    where
+        T: Send,
+        F: Fn(&mut NubDB) -> Result<T, NubError> + Sync,
+        R: FnOnce(Vec<T>) -> T,
+    {
+        let op = &op;
+        let results: Vec<Result<T, NubError>> = thread::scope(|scope| {
+            let workers: Vec<_> = self.nodes.iter_mut().map(|node| scope.spawn(move || op(node))).collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                .collect()
+        });
+        Ok(reduce(results.into_iter().collect::<Result<_, _>>()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::mock_node;
+
+    #[test]
+    fn test_map_reduce_sums_across_nodes() {
+        let addrs: Vec<String> = ["3", "4", "5"]
+            .into_iter()
+            .map(|count| mock_node(move |cmd| if cmd == "GET hits" { format!("\"{}\"", count) } else { "OK".to_string() }).0)
+            .collect();
+        let addrs: Vec<&str> = addrs.iter().map(String::as_str).collect();
+        let mut nodes = MultiNode::connect(&addrs).unwrap();
+        assert_eq!(nodes.len(), 3);
+
+        let total = nodes.map_reduce(|node| Ok(node.get_typed::<i64>("hits")?.unwrap_or(0)), |counts| counts.into_iter().sum());
+        assert_eq!(total.unwrap(), 12);
+        let order = nodes.map_reduce(|node| Ok(vec![node.get("hits")?.unwrap()]), |lists| lists.concat());
+        assert_eq!(order.unwrap(), ["3", "4", "5"]);
+
+        let failed = nodes.map_reduce(|node| node.incr("hits"), |counts| counts.into_iter().sum());
+        assert!(failed.is_err());
+    }
+}
@@ -0,0 +1,472 @@
+//! Key prefixes that keep services sharing a server out of each other's keys
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime};
+
+use super::{validate_key, ExpireOption, KeyType, NubDB, NubError};
+
+/// A view of a client where every key gets the same prefix
+///
+/// Created by [`NubDB::namespace`]. Keys passed in are sent with the prefix
+/// in front, and keys coming back, from [`Namespaced::scan`],
+/// [`Namespaced::keys`] and [`Namespaced::blpop`], have it removed again.
+/// Scans and key listings only ever return keys inside the namespace.
+///
+/// ```no_run
+/// # use nubdb::{NubDB, NubError};
+/// # fn main() -> Result<(), NubError> {
+/// let mut client = NubDB::connect("localhost:6379")?;
+/// let mut users = client.namespace("svc:users:");
+/// users.set("42", "Ada", None)?; // stored as svc:users:42
+/// assert_eq!(users.keys("*")?, vec!["42".to_string()]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Namespaced<'a> {
+    client: &'a mut NubDB,
+    prefix: String,
+}
+
+impl NubDB {
+    /// Prefix every key with `prefix`, see [`Namespaced`]
+    pub fn namespace(&mut self, prefix: &str) -> Namespaced<'_> {
+        Namespaced { client: self, prefix: prefix.to_string() }
+    }
+}
+
+impl<'a> Namespaced<'a> {
+    /// The prefix added to every key
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The client keys are sent through, for commands without a prefix
+    pub fn client(&mut self) -> &mut NubDB {
+        self.client
+    }
+
+    /// SET key-value pair
+    pub fn set(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.client.set(&self.key(key)?, value, ttl)
+    }
+
+    /// SET key-value pair with a `Duration` TTL
+    pub fn set_with_duration(&mut self, key: &str, value: &str, ttl: Option<Duration>) -> Result<bool, NubError> {
+        self.client.set_with_duration(&self.key(key)?, value, ttl)
+    }
+
+    /// SETEX set a value with a TTL in seconds
+    pub fn setex(&mut self, key: &str, value: &str, seconds: u32) -> Result<(), NubError> {
+        self.client.setex(&self.key(key)?, value, seconds)
+    }
+
+    /// PSETEX set a value with a TTL in milliseconds
+    pub fn psetex(&mut self, key: &str, value: &str, millis: u64) -> Result<(), NubError> {
+        self.client.psetex(&self.key(key)?, value, millis)
+    }
+
+    /// SETNX set a value only if the key does not exist
+    pub fn set_nx(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.client.set_nx(&self.key(key)?, value, ttl)
+    }
+
+    /// MSET key-value pairs
+    pub fn mset(&mut self, pairs: &[(&str, &str)]) -> Result<bool, NubError> {
+        let keys = pairs.iter().map(|(key, _)| self.key(key)).collect::<Result<Vec<_>, _>>()?;
+        let pairs: Vec<(&str, &str)> = keys.iter().zip(pairs).map(|(key, (_, value))| (key.as_str(), *value)).collect();
+        self.client.mset(&pairs)
+    }
+
+    /// SET a binary value
+    pub fn set_bytes(&mut self, key: &str, value: &[u8], ttl: Option<u32>) -> Result<bool, NubError> {
+        self.client.set_bytes(&self.key(key)?, value, ttl)
+    }
+
+    /// SET a value of `len` bytes streamed from `reader`
+    pub fn set_from(&mut self, key: &str, reader: &mut dyn Read, len: u64, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.client.set_from(&self.key(key)?, reader, len, ttl)
+    }
+
+    /// GET a binary value
+    pub fn get_bytes(&mut self, key: &str) -> Result<Option<Vec<u8>>, NubError> {
+        self.client.get_bytes(&self.key(key)?)
+    }
+
+    /// GET a value, streaming it into `out`
+    pub fn get_into(&mut self, key: &str, out: &mut dyn Write) -> Result<bool, NubError> {
+        self.client.get_into(&self.key(key)?, out)
+    }
+
+    /// GET value by key
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        self.client.get(&self.key(key)?)
+    }
+
+    /// SET a value using its `Display` representation
+    pub fn set_typed<T: fmt::Display>(&mut self, key: &str, value: &T, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.client.set_typed(&self.key(key)?, value, ttl)
+    }
+
+    /// GET a value and parse it with `FromStr`
+    pub fn get_typed<T>(&mut self, key: &str) -> Result<Option<T>, NubError>
+    where
+        T: std::str::FromStr,
+        T::Err: fmt::Display,
+    {
+        self.client.get_typed(&self.key(key)?)
+    }
+
+    /// SET a value serialized as JSON
+    #[cfg(feature = "serde")]
+    pub fn set_json<T: serde::Serialize>(&mut self, key: &str, value: &T, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.client.set_json(&self.key(key)?, value, ttl)
+    }
+
+    /// GET a value stored as JSON and deserialize it
+    #[cfg(feature = "serde")]
+    pub fn get_json<T: serde::de::DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>, NubError> {
+        self.client.get_json(&self.key(key)?)
+    }
+
+    /// GETSET atomically replace a value, returning the previous one
+    pub fn get_set(&mut self, key: &str, value: &str) -> Result<Option<String>, NubError> {
+        self.client.get_set(&self.key(key)?, value)
+    }
+
+    /// APPEND a value, returning the new length
+    pub fn append(&mut self, key: &str, value: &str) -> Result<usize, NubError> {
+        self.client.append(&self.key(key)?, value)
+    }
+
+    /// STRLEN length of a value
+    pub fn strlen(&mut self, key: &str) -> Result<usize, NubError> {
+        self.client.strlen(&self.key(key)?)
+    }
+
+    /// GETRANGE part of a value
+    pub fn get_range(&mut self, key: &str, start: i64, end: i64) -> Result<String, NubError> {
+        self.client.get_range(&self.key(key)?, start, end)
+    }
+
+    /// SETRANGE overwrite part of a value
+    pub fn set_range(&mut self, key: &str, offset: u64, value: &str) -> Result<usize, NubError> {
+        self.client.set_range(&self.key(key)?, offset, value)
+    }
+
+    /// SETBIT set one bit of a value
+    pub fn set_bit(&mut self, key: &str, offset: u64, value: bool) -> Result<bool, NubError> {
+        self.client.set_bit(&self.key(key)?, offset, value)
+    }
+
+    /// GETBIT read one bit of a value
+    pub fn get_bit(&mut self, key: &str, offset: u64) -> Result<bool, NubError> {
+        self.client.get_bit(&self.key(key)?, offset)
+    }
+
+    /// BITCOUNT count the set bits of a value
+    pub fn bit_count(&mut self, key: &str) -> Result<u64, NubError> {
+        self.client.bit_count(&self.key(key)?)
+    }
+
+    /// GETDEL read a value and delete it
+    pub fn get_del(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        self.client.get_del(&self.key(key)?)
+    }
+
+    /// MGET values for several keys
+    pub fn mget(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>, NubError> {
+        let keys = self.keys_in(keys)?;
+        self.client.mget(&as_strs(&keys))
+    }
+
+    /// DELETE key
+    pub fn delete(&mut self, key: &str) -> Result<bool, NubError> {
+        self.client.delete(&self.key(key)?)
+    }
+
+    /// DEL keys, returning how many existed
+    pub fn del_many(&mut self, keys: &[&str]) -> Result<usize, NubError> {
+        let keys = self.keys_in(keys)?;
+        self.client.del_many(&as_strs(&keys))
+    }
+
+    /// TYPE the kind of value stored at key
+    pub fn key_type(&mut self, key: &str) -> Result<Option<KeyType>, NubError> {
+        self.client.key_type(&self.key(key)?)
+    }
+
+    /// TOUCH refresh the access time of `keys`
+    pub fn touch(&mut self, keys: &[&str]) -> Result<usize, NubError> {
+        let keys = self.keys_in(keys)?;
+        self.client.touch(&as_strs(&keys))
+    }
+
+    /// RENAME a key within the namespace
+    pub fn rename(&mut self, src: &str, dst: &str) -> Result<(), NubError> {
+        self.client.rename(&self.key(src)?, &self.key(dst)?)
+    }
+
+    /// COPY a value to another key within the namespace
+    pub fn copy(&mut self, src: &str, dst: &str, replace: bool) -> Result<bool, NubError> {
+        self.client.copy(&self.key(src)?, &self.key(dst)?, replace)
+    }
+
+    /// EXISTS check if key exists
+    pub fn exists(&mut self, key: &str) -> Result<bool, NubError> {
+        self.client.exists(&self.key(key)?)
+    }
+
+    /// EXISTS count how many of `keys` exist
+    pub fn exists_many(&mut self, keys: &[&str]) -> Result<usize, NubError> {
+        let keys = self.keys_in(keys)?;
+        self.client.exists_many(&as_strs(&keys))
+    }
+
+    /// INCR increment counter
+    pub fn incr(&mut self, key: &str) -> Result<i64, NubError> {
+        self.client.incr(&self.key(key)?)
+    }
+
+    /// DECR decrement counter
+    pub fn decr(&mut self, key: &str) -> Result<i64, NubError> {
+        self.client.decr(&self.key(key)?)
+    }
+
+    /// INCRBY increment counter by `amount`
+    pub fn incr_by(&mut self, key: &str, amount: i64) -> Result<i64, NubError> {
+        self.client.incr_by(&self.key(key)?, amount)
+    }
+
+    /// DECRBY decrement counter by `amount`
+    pub fn decr_by(&mut self, key: &str, amount: i64) -> Result<i64, NubError> {
+        self.client.decr_by(&self.key(key)?, amount)
+    }
+
+    /// INCRBYFLOAT increment a floating point counter
+    pub fn incr_by_float(&mut self, key: &str, amount: f64) -> Result<f64, NubError> {
+        self.client.incr_by_float(&self.key(key)?, amount)
+    }
+
+    /// EXPIRE set a key's time to live in seconds
+    pub fn expire(&mut self, key: &str, seconds: u32) -> Result<bool, NubError> {
+        self.client.expire(&self.key(key)?, seconds)
+    }
+
+    /// EXPIRE with a condition on the current time to live
+    pub fn expire_with(&mut self, key: &str, seconds: u32, option: ExpireOption) -> Result<bool, NubError> {
+        self.client.expire_with(&self.key(key)?, seconds, option)
+    }
+
+    /// EXPIREAT expire a key at a point in time
+    pub fn expire_at(&mut self, key: &str, timestamp: SystemTime) -> Result<bool, NubError> {
+        self.client.expire_at(&self.key(key)?, timestamp)
+    }
+
+    /// TTL remaining time to live in seconds
+    pub fn ttl(&mut self, key: &str) -> Result<Option<i64>, NubError> {
+        self.client.ttl(&self.key(key)?)
+    }
+
+    /// TTL remaining time to live as a `Duration`
+    pub fn ttl_duration(&mut self, key: &str) -> Result<Option<Duration>, NubError> {
+        self.client.ttl_duration(&self.key(key)?)
+    }
+
+    /// PERSIST remove a key's time to live
+    pub fn persist(&mut self, key: &str) -> Result<bool, NubError> {
+        self.client.persist(&self.key(key)?)
+    }
+
+    /// HSET set a hash field
+    pub fn hset(&mut self, key: &str, field: &str, value: &str) -> Result<bool, NubError> {
+        self.client.hset(&self.key(key)?, field, value)
+    }
+
+    /// HGET read a hash field
+    pub fn hget(&mut self, key: &str, field: &str) -> Result<Option<String>, NubError> {
+        self.client.hget(&self.key(key)?, field)
+    }
+
+    /// HGETALL read every field of a hash
+    pub fn hgetall(&mut self, key: &str) -> Result<HashMap<String, String>, NubError> {
+        self.client.hgetall(&self.key(key)?)
+    }
+
+    /// LPUSH push onto the head of a list
+    pub fn lpush(&mut self, key: &str, value: &str) -> Result<usize, NubError> {
+        self.client.lpush(&self.key(key)?, value)
+    }
+
+    /// RPUSH push onto the tail of a list
+    pub fn rpush(&mut self, key: &str, value: &str) -> Result<usize, NubError> {
+        self.client.rpush(&self.key(key)?, value)
+    }
+
+    /// LPOP pop from the head of a list
+    pub fn lpop(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        self.client.lpop(&self.key(key)?)
+    }
+
+    /// RPOP pop from the tail of a list
+    pub fn rpop(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        self.client.rpop(&self.key(key)?)
+    }
+
+    /// BLPOP pop from the head of a list, waiting up to `timeout`
+    ///
+    /// The key in the result has the prefix removed.
+    pub fn blpop(&mut self, key: &str, timeout: Duration) -> Result<Option<(String, String)>, NubError> {
+        let popped = self.client.blpop(&self.key(key)?, timeout)?;
+        Ok(popped.map(|(key, value)| (self.strip(key), value)))
+    }
+
+    /// LRANGE read part of a list
+    pub fn lrange(&mut self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, NubError> {
+        self.client.lrange(&self.key(key)?, start, stop)
+    }
+
+    /// SADD add a set member
+    pub fn sadd(&mut self, key: &str, member: &str) -> Result<bool, NubError> {
+        self.client.sadd(&self.key(key)?, member)
+    }
+
+    /// SREM remove a set member
+    pub fn srem(&mut self, key: &str, member: &str) -> Result<bool, NubError> {
+        self.client.srem(&self.key(key)?, member)
+    }
+
+    /// SISMEMBER check set membership
+    pub fn sismember(&mut self, key: &str, member: &str) -> Result<bool, NubError> {
+        self.client.sismember(&self.key(key)?, member)
+    }
+
+    /// SMISMEMBER check several set members at once
+    pub fn smismember(&mut self, key: &str, members: &[&str]) -> Result<Vec<bool>, NubError> {
+        self.client.smismember(&self.key(key)?, members)
+    }
+
+    /// SMEMBERS read every member of a set
+    pub fn smembers(&mut self, key: &str) -> Result<HashSet<String>, NubError> {
+        self.client.smembers(&self.key(key)?)
+    }
+
+    /// ZADD add a sorted set member with a score
+    pub fn zadd(&mut self, key: &str, score: f64, member: &str) -> Result<bool, NubError> {
+        self.client.zadd(&self.key(key)?, score, member)
+    }
+
+    /// ZSCORE read a sorted set member's score
+    pub fn zscore(&mut self, key: &str, member: &str) -> Result<Option<f64>, NubError> {
+        self.client.zscore(&self.key(key)?, member)
+    }
+
+    /// ZRANGE read sorted set members by rank
+    pub fn zrange(&mut self, key: &str, start: i64, stop: i64, with_scores: bool) -> Result<Vec<(String, Option<f64>)>, NubError> {
+        self.client.zrange(&self.key(key)?, start, stop, with_scores)
+    }
+
+    /// Iterate over keys in the namespace, with the prefix removed
+    ///
+    /// `pattern` is matched against the key without the prefix.
+    pub fn scan(&mut self, pattern: Option<&str>) -> impl Iterator<Item = Result<String, NubError>> + '_ {
+        let pattern = glob_pattern(&self.prefix, pattern.unwrap_or("*"));
+        let prefix = self.prefix.as_str();
+        self.client.scan(Some(pattern.as_str())).filter_map(move |key| match key {
+            Ok(key) => key.strip_prefix(prefix).map(|key| Ok(key.to_string())),
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    /// KEYS list keys in the namespace matching `pattern`, with the prefix removed
+    pub fn keys(&mut self, pattern: &str) -> Result<Vec<String>, NubError> {
+        let keys = self.client.keys(&glob_pattern(&self.prefix, pattern))?;
+        Ok(keys.into_iter().filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_string)).collect())
+    }
+
+    fn key(&self, key: &str) -> Result<String, NubError> {
+        validate_key(key)?;
+        Ok(format!("{}{}", self.prefix, key))
+    }
+
+    fn keys_in(&self, keys: &[&str]) -> Result<Vec<String>, NubError> {
+        keys.iter().map(|key| self.key(key)).collect()
+    }
+
+    fn strip(&self, key: String) -> String {
+        match key.strip_prefix(&self.prefix) {
+            Some(stripped) => stripped.to_string(),
+            None => key,
+        }
+    }
+}
+
+/// A glob pattern matching `pattern` inside the namespace `prefix`
+///
+/// Glob characters in the prefix are matched literally. Results are still
+/// checked against the prefix, in case the server reads the pattern
+/// differently.
+fn glob_pattern(prefix: &str, pattern: &str) -> String {
+    let mut glob = String::with_capacity(prefix.len() + pattern.len());
+    for c in prefix.chars() {
+        match c {
+            '*' | '?' | '[' => {
+                glob.push('[');
+                glob.push(c);
+                glob.push(']');
+            }
+            _ => glob.push(c),
+        }
+    }
+    glob.push_str(pattern);
+    glob
+}
+
+fn as_strs(keys: &[String]) -> Vec<&str> {
+    keys.iter().map(String::as_str).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_namespace_isolates_keys() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        client.set("ns:other:1", "outside", None).unwrap();
+
+        let mut users = client.namespace("ns:users:");
+        assert!(users.set("1", "Ada", None).unwrap());
+        assert!(users.mset(&[("2", "Grace"), ("3", "Linus")]).unwrap());
+        assert_eq!(users.get("1").unwrap().as_deref(), Some("Ada"));
+        assert_eq!(
+            users.mget(&["2", "3", "4"]).unwrap(),
+            vec![Some("Grace".to_string()), Some("Linus".to_string()), None]
+        );
+
+        let mut keys = users.keys("*").unwrap();
+        keys.sort();
+        assert_eq!(keys, ["1", "2", "3"]);
+        let mut scanned = users.scan(None).collect::<Result<Vec<_>, _>>().unwrap();
+        scanned.sort();
+        assert_eq!(scanned, ["1", "2", "3"]);
+        assert_eq!(users.keys("2*").unwrap(), ["2"]);
+
+        assert!(matches!(users.get(""), Err(NubError::InvalidKey(_))));
+        assert_eq!(users.del_many(&["1", "2", "3"]).unwrap(), 3);
+        assert_eq!(client.get("ns:other:1").unwrap().as_deref(), Some("outside"));
+        assert_eq!(client.get("ns:users:1").unwrap(), None);
+
+        client.delete("ns:other:1").unwrap();
+        client.close().unwrap();
+    }
+
+    #[test]
+    fn test_glob_pattern_escapes_prefix() {
+        assert_eq!(glob_pattern("svc:users:", "*"), "svc:users:*");
+        assert_eq!(glob_pattern("tenant[1]*:", "*"), "tenant[[]1][*]:*");
+        assert_eq!(glob_pattern("tenant?:", "user:?"), "tenant[?]:user:?");
+    }
+}
@@ -0,0 +1,57 @@
+//! Hooks for recording per-command metrics
+
+use std::time::Duration;
+
+use super::{NubError, Response};
+
+/// Called after every command, see [`NubDBBuilder::observer`]
+///
+/// The observer runs on the thread that sent the command, between the reply
+/// arriving and the result being returned, so it should be quick: record a
+/// histogram sample or bump a counter and move on.
+///
+/// ```
+/// # use std::sync::atomic::{AtomicU64, Ordering};
+/// # use std::time::Duration;
+/// # use nubdb::{CommandObserver, NubError, Response};
+/// #[derive(Default)]
+/// struct ErrorCounter(AtomicU64);
+///
+/// impl CommandObserver for ErrorCounter {
+///     fn on_command(&self, _name: &str, _duration: Duration, result: Result<&Response, &NubError>) {
+///         if result.is_err() {
+///             self.0.fetch_add(1, Ordering::Relaxed);
+///         }
+///     }
+/// }
+/// ```
+///
+/// [`NubDBBuilder::observer`]: super::NubDBBuilder::observer
+pub trait CommandObserver: Send + Sync {
+    /// `name` is the command name only, such as `GET`; keys, values and
+    /// passwords are never passed. `duration` covers the whole call,
+    /// including any reconnects and retries.
+    fn on_command(&self, name: &str, duration: Duration, result: Result<&Response, &NubError>);
+
+    /// Called by a [`Pipeline`] or [`AutoPipeline`] whenever its queue or
+    /// in-flight batch changes size, with its [`queued_len`] and
+    /// [`inflight_len`]
+    ///
+    /// Sampled over time, `queued` against an auto pipeline's
+    /// `max_commands`, or a pipeline's [`max_queued`], shows how full
+    /// batches get before they are sent. Does nothing by default.
+    ///
+    /// [`Pipeline`]: super::Pipeline
+    /// [`AutoPipeline`]: super::AutoPipeline
+    /// [`queued_len`]: super::AutoPipeline::queued_len
+    /// [`inflight_len`]: super::AutoPipeline::inflight_len
+    /// [`max_queued`]: super::Pipeline::max_queued
+    fn on_pipeline(&self, queued: usize, in_flight: usize) {
+        let _ = (queued, in_flight);
+    }
+}
+
+/// The command name at the start of a command line
+pub(crate) fn command_name(cmd: &str) -> &str {
+    cmd.split(' ').next().unwrap_or(cmd)
+}
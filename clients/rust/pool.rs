@@ -0,0 +1,200 @@
+//! Thread-safe connection pool
+
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use super::{NubDB, NubError};
+
+/// How long an idle connection has to answer the PING sent on checkout
+const VALIDATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A pool of [`NubDB`] connections shared between threads
+///
+/// Connections are opened lazily, up to `max_size`. [`NubPool::get`] hands
+/// out an idle connection, opens a new one if the cap has not been reached,
+/// or blocks until another thread returns one. Cloning a `NubPool` is cheap
+/// and every clone shares the same connections.
+///
+/// An idle connection is checked with a PING before it is handed out and
+/// replaced if the server does not answer within a second. This costs a
+/// round trip per checkout, but a connection left half-open by a network
+/// partition never reaches the caller.
+#[derive(Clone)]
+pub struct NubPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    addr: String,
+    max_size: usize,
+    state: Mutex<PoolState>,
+    available: Condvar,
+}
+
+struct PoolState {
+    idle: Vec<NubDB>,
+    /// Connections currently open, idle or checked out
+    total: usize,
+}
+
+impl NubPool {
+    /// Create a pool for `addr` holding at most `max_size` connections
+    ///
+    /// No connection is opened until the first call to [`NubPool::get`].
+    pub fn new(addr: &str, max_size: usize) -> Self {
+        NubPool {
+            inner: Arc::new(PoolInner {
+                addr: addr.to_string(),
+                max_size: max_size.max(1),
+                state: Mutex::new(PoolState { idle: Vec::new(), total: 0 }),
+                available: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Check out a connection, blocking while all of them are in use
+    pub fn get(&self) -> Result<PooledConn, NubError> {
+        let mut state = self.lock();
+        loop {
+            if let Some(mut conn) = state.idle.pop() {
+                // Check without holding the lock; a dead connection frees its slot
+                drop(state);
+                if conn.validate(VALIDATE_TIMEOUT).is_ok() {
+                    return Ok(PooledConn { conn: Some(conn), pool: self.inner.clone() });
+                }
+                drop(conn);
+                self.inner.release_slot();
+                state = self.lock();
+                continue;
+            }
+
+            if state.total < self.inner.max_size {
+                // Reserve the slot, then connect without holding the lock
+                state.total += 1;
+                drop(state);
+
+                return match NubDB::connect(&self.inner.addr) {
+                    Ok(conn) => Ok(PooledConn { conn: Some(conn), pool: self.inner.clone() }),
+                    Err(e) => {
+                        self.inner.release_slot();
+                        Err(e)
+                    }
+                };
+            }
+
+            state = self.inner.available.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    /// Number of idle connections ready to be handed out
+    pub fn idle_count(&self) -> usize {
+        self.lock().idle.len()
+    }
+
+    /// Maximum number of connections the pool will open
+    pub fn max_size(&self) -> usize {
+        self.inner.max_size
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.inner.lock()
+    }
+}
+
+impl PoolInner {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        // A panic while holding the lock cannot leave the state inconsistent
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Give up a slot whose connection was dropped or never opened
+    fn release_slot(&self) {
+        self.lock().total -= 1;
+        self.available.notify_one();
+    }
+}
+
+/// A connection checked out of a [`NubPool`]
+///
+/// Dereferences to [`NubDB`]. Dropping the guard returns the connection to
+/// the pool, unless a socket error occurred while it was checked out, in
+/// which case it is closed and a fresh one is opened on a later
+/// [`NubPool::get`].
+pub struct PooledConn {
+    conn: Option<NubDB>,
+    pool: Arc<PoolInner>,
+}
+
+impl Deref for PooledConn {
+    type Target = NubDB;
+
+    fn deref(&self) -> &NubDB {
+        self.conn.as_ref().expect("connection taken")
+    }
+}
+
+impl DerefMut for PooledConn {
+    fn deref_mut(&mut self) -> &mut NubDB {
+        self.conn.as_mut().expect("connection taken")
+    }
+}
+
+impl Drop for PooledConn {
+    fn drop(&mut self) {
+        let Some(conn) = self.conn.take() else { return };
+
+        if conn.is_broken() {
+            drop(conn);
+            self.pool.release_slot();
+        } else {
+            self.pool.lock().idle.push(conn);
+            self.pool.available.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_pool_caps_connections() {
+        let pool = NubPool::new("localhost:6379", 2);
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    let key = format!("pool:{}", i);
+                    assert!(conn.set(&key, "value", None).unwrap());
+                    assert_eq!(conn.get(&key).unwrap(), Some("value".to_string()));
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert!(pool.idle_count() <= pool.max_size());
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_pool_replaces_dead_connections() {
+        let pool = NubPool::new("localhost:6379", 1);
+
+        // A socket that died while idle is never handed out
+        let conn = pool.get().unwrap();
+        crate::tests::break_socket(&conn);
+        drop(conn);
+        assert_eq!(pool.idle_count(), 1);
+
+        let mut conn = pool.get().unwrap();
+        assert!(conn.is_connected());
+        assert!(conn.set("pool:replaced", "value", None).unwrap());
+    }
+}
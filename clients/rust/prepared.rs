@@ -0,0 +1,129 @@
+//! Command templates parsed once and sent many times
+
+use super::{NubDB, NubError, QuoteStyle, Response};
+
+/// A command line with `{}` placeholders for its arguments, see
+/// [`NubDB::prepare`]
+///
+/// The template is checked once, when prepared; sending it with
+/// [`NubDB::execute`] only copies the fixed text and the escaped arguments
+/// into one buffer sized up front.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreparedCommand {
+    /// The text around the placeholders, one more than there are placeholders
+    parts: Vec<String>,
+}
+
+impl PreparedCommand {
+    /// Parse `template`, such as `GET {}` or `SETEX {} 60 {}`
+    ///
+    /// Each `{}` stands for one whole argument. The template must start with
+    /// a command name and may not contain a newline, a `{}` joined to other
+    /// text, or any other `{` or `}`.
+    pub fn new(template: &str) -> Result<Self, NubError> {
+        let invalid = |reason: &str| NubError::InvalidArgument(format!("invalid command template {:?}: {}", template, reason));
+        if template.contains(['\n', '\r']) {
+            return Err(invalid("contains a newline"));
+        }
+        match template.split(' ').next() {
+            Some(name) if !name.is_empty() && !name.contains(['{', '}']) => {}
+            _ => return Err(invalid("does not start with a command name")),
+        }
+
+        let parts: Vec<String> = template.split("{}").map(str::to_string).collect();
+        if parts.iter().any(|part| part.contains(['{', '}'])) {
+            return Err(invalid("has a brace that is not part of a placeholder"));
+        }
+        let joined = parts.windows(2).any(|pair| !pair[0].ends_with(' ') || !(pair[1].is_empty() || pair[1].starts_with(' ')));
+        if joined {
+            return Err(invalid("has a placeholder that is not a whole argument"));
+        }
+        Ok(PreparedCommand { parts })
+    }
+
+    /// How many arguments [`NubDB::execute`] must be given
+    pub fn placeholders(&self) -> usize {
+        self.parts.len() - 1
+    }
+
+    /// The command line with `args` filled in, quoted like values in `style`
+    fn render(&self, args: &[&str], style: QuoteStyle) -> Result<String, NubError> {
+        if args.len() != self.placeholders() {
+            return Err(NubError::InvalidArgument(format!(
+                "command template takes {} arguments, got {}", self.placeholders(), args.len()
+            )));
+        }
+
+        let fixed: usize = self.parts.iter().map(String::len).sum();
+        let mut cmd = String::with_capacity(fixed + args.iter().map(|arg| arg.len() + 2).sum::<usize>());
+        cmd.push_str(&self.parts[0]);
+        for (arg, part) in args.iter().zip(&self.parts[1..]) {
+            cmd.push_str(&style.quote(arg)?);
+            cmd.push_str(part);
+        }
+        Ok(cmd)
+    }
+}
+
+impl NubDB {
+    /// Parse a command template once, to send with [`NubDB::execute`]
+    ///
+    /// ```no_run
+    /// # use nubdb::NubDB;
+    /// let mut client = NubDB::connect("localhost:6379")?;
+    /// let touch = client.prepare("SETEX {} 60 {}")?;
+    /// for id in ["1", "2", "3"] {
+    ///     client.execute(&touch, &[&format!("session:{}", id), "active"])?;
+    /// }
+    /// # Ok::<(), nubdb::NubError>(())
+    /// ```
+    ///
+    /// See [`PreparedCommand::new`] for what a template may contain.
+    pub fn prepare(&self, template: &str) -> Result<PreparedCommand, NubError> {
+        PreparedCommand::new(template)
+    }
+
+    /// Send `prepared` with its placeholders filled from `args`, in order
+    ///
+    /// Every argument is quoted like a value, as with [`NubDB::command`],
+    /// and the reply is returned the same way. Passing more or fewer
+    /// arguments than the template has placeholders is
+    /// `NubError::InvalidArgument`, and nothing is sent.
+    pub fn execute(&mut self, prepared: &PreparedCommand, args: &[&str]) -> Result<Response, NubError> {
+        let cmd = prepared.render(args, self.config.quote_style)?;
+        self.send_command(&cmd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::mock_node;
+
+    #[test]
+    fn test_prepared_templates() {
+        let setex = PreparedCommand::new("SETEX {} 60 {}").unwrap();
+        assert_eq!(setex.placeholders(), 2);
+        assert_eq!(setex.render(&["k", r#"say "hi""#], QuoteStyle::Double).unwrap(), r#"SETEX "k" 60 "say \"hi\"""#);
+        assert!(matches!(setex.render(&["k"], QuoteStyle::Double), Err(NubError::InvalidArgument(_))));
+        assert_eq!(PreparedCommand::new("PING").unwrap().render(&[], QuoteStyle::Double).unwrap(), "PING");
+
+        for template in ["", "{} k", "GET user:{}", "GET {}x", "GET {}\n", "GET {k}", "GET }"] {
+            assert!(matches!(PreparedCommand::new(template), Err(NubError::InvalidArgument(_))), "{:?}", template);
+        }
+    }
+
+    #[test]
+    fn test_execute_prepared() {
+        let (addr, seen) = mock_node(|cmd| match cmd {
+            r#"GET "a b""# => r#""v""#.to_string(),
+            _ => "ERR unknown key".to_string(),
+        });
+        let mut client = NubDB::connect(&addr).unwrap();
+        let get = client.prepare("GET {}").unwrap();
+        assert_eq!(client.execute(&get, &["a b"]).unwrap(), Response::Bulk(Some("v".to_string())));
+        assert!(matches!(client.execute(&get, &["c"]), Err(NubError::Protocol(_))));
+        assert!(matches!(client.execute(&get, &[]), Err(NubError::InvalidArgument(_))));
+        assert_eq!(*seen.lock().unwrap(), vec![r#"GET "a b""#, r#"GET "c""#]);
+    }
+}
@@ -0,0 +1,439 @@
+//! How commands and replies are framed on the wire
+
+use std::fmt;
+use std::io::{self, BufRead, Read};
+
+use super::{escape_value, unescape_value, NubError, QuoteStyle, Response};
+
+/// Frames commands and replies for one version of the wire protocol
+///
+/// Set with [`NubDBBuilder::protocol`]; without one the client speaks
+/// [`TextProtocol`]. The typed methods build each command as a text line,
+/// which is split into its arguments, quotes removed and escapes undone,
+/// before being handed to [`Protocol::encode_command`].
+///
+/// ```
+/// # use std::io::BufRead;
+/// # use nubdb::{NubError, Protocol, Response, TextProtocol};
+/// /// Commands as `|`-separated lines; replies as the text protocol sends them
+/// struct Piped;
+///
+/// impl Protocol for Piped {
+///     fn encode_command(&self, args: &[&str]) -> Vec<u8> {
+///         format!("{}\n", args.join("|")).into_bytes()
+///     }
+///
+///     fn decode_response(&self, reader: &mut dyn BufRead) -> Result<Response, NubError> {
+///         TextProtocol.decode_response(reader)
+///     }
+/// }
+/// ```
+///
+/// [`NubDBBuilder::protocol`]: super::NubDBBuilder::protocol
+pub trait Protocol: Send + Sync {
+    /// Encode one command, `args[0]` being its name
+    ///
+    /// Arguments are passed as the server should receive them, unquoted and
+    /// unescaped.
+    fn encode_command(&self, args: &[&str]) -> Vec<u8>;
+
+    /// Encode one command for a client set to end lines with `line_ending`
+    ///
+    /// This is what the client calls. By default it ignores the line ending
+    /// and calls [`Protocol::encode_command`], which suits protocols that
+    /// end commands their own way; a line-based protocol can override it to
+    /// follow [`NubDBBuilder::line_ending`].
+    ///
+    /// [`NubDBBuilder::line_ending`]: super::NubDBBuilder::line_ending
+    fn encode_command_with_ending(&self, args: &[&str], line_ending: LineEnding) -> Vec<u8> {
+        let _ = line_ending;
+        self.encode_command(args)
+    }
+
+    /// Read one complete reply
+    ///
+    /// Error replies are returned as `Response::Error`. Any `Err` other than
+    /// `NubError::Parse` is taken to mean the reply was not fully read, and
+    /// the connection is marked broken.
+    fn decode_response(&self, reader: &mut dyn BufRead) -> Result<Response, NubError>;
+}
+
+/// How the client ends each command line, see [`NubDBBuilder::line_ending`]
+///
+/// Replies may end their lines either way whatever is set here.
+///
+/// [`NubDBBuilder::line_ending`]: super::NubDBBuilder::line_ending
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    /// `\n`, which every NubDB server accepts
+    #[default]
+    Lf,
+    /// `\r\n`, for servers that require it
+    CrLf,
+}
+
+impl LineEnding {
+    /// The terminator as written to the socket
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+}
+
+/// The line-based protocol NubDB servers speak, and the client's default
+///
+/// A command is one line: the name, then its arguments separated by spaces,
+/// each in double quotes with `"`, `\`, newline and carriage return escaped
+/// if it would not otherwise read back as a single word, and ended with
+/// `\n`, or with the client's [`NubDBBuilder::line_ending`]. Replies are
+/// read as described on [`Response`], following `*<count>` and `$<len>`
+/// framing.
+///
+/// [`NubDBBuilder::line_ending`]: super::NubDBBuilder::line_ending
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextProtocol;
+
+impl Protocol for TextProtocol {
+    fn encode_command(&self, args: &[&str]) -> Vec<u8> {
+        self.encode_command_with_ending(args, LineEnding::Lf)
+    }
+
+    fn encode_command_with_ending(&self, args: &[&str], line_ending: LineEnding) -> Vec<u8> {
+        let mut line = String::new();
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                line.push(' ');
+            }
+            if i == 0 || is_word(arg) {
+                line.push_str(arg);
+            } else {
+                line.push('"');
+                line.push_str(&escape_value(arg));
+                line.push('"');
+            }
+        }
+        let mut encoded = line.into_bytes();
+        encoded.extend_from_slice(line_ending.as_bytes());
+        encoded
+    }
+
+    fn decode_response(&self, reader: &mut dyn BufRead) -> Result<Response, NubError> {
+        read_text_response(reader, &mut String::new())
+    }
+}
+
+/// Whether `arg` can be sent without quotes
+fn is_word(arg: &str) -> bool {
+    !arg.is_empty() && !arg.chars().any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '\\')
+}
+
+/// Split a command line built by the typed methods into its arguments
+///
+/// Quoted arguments run to the next unescaped quote and are unescaped;
+/// everything else is split on spaces. Double quotes are always read, as a
+/// few arguments keep them whatever the style; single quotes only under
+/// [`QuoteStyle::Single`], as elsewhere they are part of a bare word.
+pub(crate) fn command_args(line: &str, style: QuoteStyle) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut rest = line.trim_start_matches(' ');
+    while !rest.is_empty() {
+        let quote = rest.chars().next().filter(|&c| c == '"' || (c == '\'' && style == QuoteStyle::Single));
+        let end = match quote {
+            Some(quote) => {
+                let mut escaped = false;
+                let close = rest.char_indices().skip(1).find(|&(_, c)| {
+                    let found = c == quote && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    found
+                });
+                match close {
+                    Some((i, _)) if quote == '"' => {
+                        args.push(unescape_value(&rest[1..i]));
+                        i + 1
+                    }
+                    Some((i, _)) => {
+                        args.push(style.unquote(rest[..=i].to_string()));
+                        i + 1
+                    }
+                    // Unterminated, so keep it as it is
+                    None => {
+                        args.push(rest.to_string());
+                        rest.len()
+                    }
+                }
+            }
+            None => {
+                let end = rest.find(' ').unwrap_or(rest.len());
+                args.push(rest[..end].to_string());
+                end
+            }
+        };
+        rest = rest[end..].trim_start_matches(' ');
+    }
+    args
+}
+
+/// Read one text protocol reply, using `line` as the line buffer
+///
+/// - `*<count>` is followed by `count` nested replies (`*-1` is nil)
+/// - `$<len>` is followed by `len` bytes of UTF-8 text (`$-1` is nil)
+/// - anything else is a single line, see [`Response`]
+///
+/// The stream ending before the reply starts is `NubError::ConnectionClosed`.
+pub(crate) fn read_text_response<R: BufRead + ?Sized>(reader: &mut R, line: &mut String) -> Result<Response, NubError> {
+    line.clear();
+    if reader.read_line(line)? == 0 {
+        return Err(NubError::ConnectionClosed);
+    }
+    let trimmed = line.trim();
+    let array_len = trimmed.strip_prefix('*').and_then(|n| n.parse::<i64>().ok());
+    let bulk_len = trimmed.strip_prefix('$').and_then(|n| n.parse::<i64>().ok());
+
+    if let Some(count) = array_len {
+        if count < 0 {
+            return Ok(Response::Bulk(None));
+        }
+        let mut items = Vec::new();
+        for _ in 0..count {
+            items.push(read_text_response(reader, line)?);
+        }
+        return Ok(Response::Array(items));
+    }
+
+    match bulk_len {
+        Some(len) if len >= 0 => {
+            let payload = read_payload(reader, len as usize)?;
+            let text = String::from_utf8(payload)
+                .map_err(|e| NubError::Parse(format!("bulk reply is not UTF-8: {}", e)))?;
+            Ok(Response::Bulk(Some(text)))
+        }
+        Some(_) => Ok(Response::Bulk(None)),
+        None => Ok(Response::parse(trimmed)),
+    }
+}
+
+/// Read `len` payload bytes and the newline that terminates them
+///
+/// The buffer grows as bytes arrive rather than being sized from the
+/// header, so a bogus length cannot allocate more than was actually sent.
+pub(crate) fn read_payload<R: BufRead + ?Sized>(reader: &mut R, len: usize) -> Result<Vec<u8>, NubError> {
+    let mut payload = Vec::with_capacity(len.min(64 * 1024));
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-value").into());
+    }
+    read_terminator(reader, len as u64)?;
+    Ok(payload)
+}
+
+/// Consume the newline after a `len` byte payload
+pub(crate) fn read_terminator<R: BufRead + ?Sized>(reader: &mut R, len: u64) -> Result<(), NubError> {
+    let mut terminator = Vec::new();
+    reader.read_until(b'\n', &mut terminator)?;
+    if terminator != b"\n" && terminator != b"\r\n" {
+        return Err(NubError::UnexpectedResponse(format!(
+            "expected newline after {} byte payload", len
+        )));
+    }
+    Ok(())
+}
+
+/// A reader that fails once more than `limit` bytes have been read from it
+pub(crate) struct Limited<'a, R: ?Sized> {
+    inner: &'a mut R,
+    limit: usize,
+    remaining: usize,
+}
+
+impl<'a, R: BufRead + ?Sized> Limited<'a, R> {
+    pub(crate) fn new(inner: &'a mut R, limit: usize) -> Self {
+        Limited { inner, limit, remaining: limit }
+    }
+}
+
+impl<R: BufRead + ?Sized> Read for Limited<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead + ?Sized> BufRead for Limited<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.remaining == 0 {
+            return Err(io::Error::other(TooLarge(self.limit)));
+        }
+        let available = self.inner.fill_buf()?;
+        Ok(&available[..available.len().min(self.remaining)])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.remaining -= amt;
+        self.inner.consume(amt);
+    }
+}
+
+/// A reader that keeps a copy of every byte read through it
+pub(crate) struct Recorded<R> {
+    inner: R,
+    bytes: Vec<u8>,
+}
+
+impl<R: BufRead> Recorded<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Recorded { inner, bytes: Vec::new() }
+    }
+
+    /// Everything read so far
+    pub(crate) fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl<R: BufRead> Read for Recorded<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Recorded<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // Already buffered, so this cannot block or fail
+        if let Ok(available) = self.inner.fill_buf() {
+            self.bytes.extend_from_slice(&available[..amt.min(available.len())]);
+        }
+        self.inner.consume(amt);
+    }
+}
+
+/// Carried in the `io::Error` from [`Limited`], and turned into
+/// `NubError::ResponseTooLarge` when converted
+#[derive(Debug)]
+pub(crate) struct TooLarge(pub(crate) usize);
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "reply is larger than {} bytes", self.0)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Write};
+    use std::sync::Arc;
+
+    use crate::NubDB;
+
+    #[test]
+    fn test_command_args_round_trip() {
+        let value = "say \"hi\"\nit's back\\slash";
+        for style in [QuoteStyle::Double, QuoteStyle::Single] {
+            let line = crate::set_command("key", value, Some(10), style).unwrap();
+            assert_eq!(command_args(&line, style), vec!["SET", "key", value, "10"]);
+        }
+        assert_eq!(command_args(r#"SET key """#, QuoteStyle::Double), vec!["SET", "key", ""]);
+        assert_eq!(command_args("SET key ''", QuoteStyle::Single), vec!["SET", "key", ""]);
+        assert_eq!(command_args("SET key 'a b'", QuoteStyle::Double), vec!["SET", "key", "'a", "b'"]);
+        assert_eq!(command_args("GET  key ", QuoteStyle::Double), vec!["GET", "key"]);
+
+        let args = ["SET", "key", "two words", "", "plain"];
+        let encoded = String::from_utf8(TextProtocol.encode_command(&args)).unwrap();
+        assert_eq!(encoded, "SET key \"two words\" \"\" plain\n");
+        assert_eq!(command_args(encoded.trim_end(), QuoteStyle::Double), args);
+    }
+
+    #[test]
+    fn test_text_protocol_follows_line_ending() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let received = std::thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut line = Vec::new();
+            BufReader::new(&socket).read_until(b'\n', &mut line).unwrap();
+            (&socket).write_all(b"OK\n").unwrap();
+            line
+        });
+
+        let mut client = NubDB::builder()
+            .protocol(Arc::new(TextProtocol))
+            .line_ending(LineEnding::CrLf)
+            .connect(&addr)
+            .unwrap();
+        assert!(client.set("k", "two words", None).unwrap());
+        assert_eq!(received.join().unwrap(), b"SET k \"two words\"\r\n");
+
+        // Called directly, `encode_command` still ends lines with `\n`
+        assert_eq!(TextProtocol.encode_command(&["GET", "k"]), b"GET k\n");
+    }
+
+    #[test]
+    fn test_text_protocol_decodes_framing() {
+        let wire = b"*3\r\nOK\r\n$5\r\nhe\nlo\r\n42\r\n\"quoted\"\n$4\nabc\n";
+        let mut reader = BufReader::new(&wire[..]);
+        assert_eq!(TextProtocol.decode_response(&mut reader).unwrap(), Response::Array(vec![
+            Response::Simple("OK".to_string()),
+            Response::Bulk(Some("he\nlo".to_string())),
+            Response::Integer(42),
+        ]));
+        assert_eq!(TextProtocol.decode_response(&mut reader).unwrap(), Response::Bulk(Some("quoted".to_string())));
+        assert!(matches!(TextProtocol.decode_response(&mut reader), Err(NubError::UnexpectedResponse(_))));
+    }
+
+    #[test]
+    fn test_custom_protocol() {
+        struct Piped;
+
+        impl Protocol for Piped {
+            fn encode_command(&self, args: &[&str]) -> Vec<u8> {
+                format!("{}\n", args.join("|")).into_bytes()
+            }
+
+            fn decode_response(&self, reader: &mut dyn BufRead) -> Result<Response, NubError> {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                match line.trim().strip_prefix('=') {
+                    Some(value) => Ok(Response::Bulk(Some(value.to_string()))),
+                    None => Ok(Response::Simple(line.trim().to_string())),
+                }
+            }
+        }
+
+        let (addr, seen) = crate::tests::mock_node(|cmd| match cmd {
+            "GET|key" => "=two words".to_string(),
+            _ => "OK".to_string(),
+        });
+        let mut client = NubDB::builder().protocol(Arc::new(Piped)).connect(&addr).unwrap();
+        assert!(client.set("key", "two words", None).unwrap());
+        assert_eq!(client.get("key").unwrap().as_deref(), Some("two words"));
+        let responses = client.pipeline().set("a", "1", None).get("key").execute().unwrap();
+        assert_eq!(responses[1], Response::Bulk(Some("two words".to_string())));
+
+        assert_eq!(*seen.lock().unwrap(), vec!["SET|key|two words", "GET|key", "SET|a|1", "GET|key"]);
+
+        // The quotes added for the style are gone by the time the protocol sees the arguments
+        let mut single = NubDB::builder()
+            .protocol(Arc::new(Piped))
+            .quote_style(QuoteStyle::Single)
+            .connect(&addr)
+            .unwrap();
+        assert!(single.set("key", "it's two words", None).unwrap());
+        single.command(&["HSET", "h", "f", "a 'b'"]).unwrap();
+        assert_eq!(seen.lock().unwrap()[4..], ["SET|key|it's two words", "HSET|h|f|a 'b'"]);
+    }
+}
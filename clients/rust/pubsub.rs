@@ -0,0 +1,228 @@
+//! Publish/subscribe
+
+use super::{NubDB, NubError, Response};
+
+/// A message published to a channel
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub channel: String,
+    pub payload: String,
+}
+
+/// What [`Subscription::next_event`] waited for
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubscriptionEvent {
+    Message(Message),
+    /// The connection was lost and a new one subscribed to every channel
+    /// again. Messages published in between were missed.
+    Reconnected,
+}
+
+/// A connection subscribed to one or more channels
+///
+/// Created by [`NubDB::subscribe`]. A subscribed connection only receives
+/// messages, so it cannot run normal commands; iterate over the
+/// subscription to receive [`Message`]s as they are published. Iteration
+/// blocks until the next message arrives, or fails with `NubError::Timeout`
+/// if the connection has a read timeout and none arrives in time.
+///
+/// If the client was built with [`NubDBBuilder::auto_reconnect`], losing the
+/// connection does not end the subscription: the client reconnects,
+/// subscribes to the same channels again and goes on waiting for messages.
+/// Iteration carries on as if nothing happened, while
+/// [`Subscription::next_event`] reports the reconnect. The server does not
+/// keep messages for a subscriber that is gone, so anything published
+/// between the connection being lost and the new subscription being
+/// confirmed is never received. If the reconnect fails, its error is
+/// returned and the next wait tries again.
+///
+/// [`Subscription::unsubscribe`] leaves every channel and hands the
+/// connection back for normal use. Dropping the subscription closes the
+/// connection, which the server treats as unsubscribing.
+///
+/// [`NubDBBuilder::auto_reconnect`]: super::NubDBBuilder::auto_reconnect
+pub struct Subscription {
+    client: NubDB,
+    channels: Vec<String>,
+    /// Set while the connection is lost and not yet subscribed again
+    lost: bool,
+}
+
+impl NubDB {
+    /// SUBSCRIBE to `channels`, turning this connection into a [`Subscription`]
+    pub fn subscribe(mut self, channels: &[&str]) -> Result<Subscription, NubError> {
+        if channels.is_empty() {
+            return Err(NubError::InvalidArgument("no channels to subscribe to".to_string()));
+        }
+
+        self.ensure_in_sync()?;
+        self.send_subscribe(channels)?;
+        Ok(Subscription {
+            client: self,
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            lost: false,
+        })
+    }
+
+    /// Send SUBSCRIBE and read the confirmations
+    pub(crate) fn send_subscribe<S: AsRef<str>>(&mut self, channels: &[S]) -> Result<(), NubError> {
+        let names: Vec<&str> = channels.iter().map(AsRef::as_ref).collect();
+        self.write_command(&format!("SUBSCRIBE {}", names.join(" ")))?;
+
+        // One confirmation per channel, in order
+        for channel in names {
+            match self.read_reply()?.into_strings()?.as_slice() {
+                [kind, confirmed, _count] if kind == "subscribe" && confirmed == channel => {}
+                other => {
+                    return Err(NubError::UnexpectedResponse(format!(
+                        "expected subscribe confirmation for {}, got {:?}", channel, other
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// PUBLISH a message, returning how many subscribers received it
+    pub fn publish(&mut self, channel: &str, payload: &str) -> Result<usize, NubError> {
+        let cmd = format!("PUBLISH {} {}", channel, self.config.quote_style.quote(payload)?);
+        self.send_command(&cmd)?.into_count()
+    }
+}
+
+impl Subscription {
+    /// Channels this connection is subscribed to
+    pub fn channels(&self) -> &[String] {
+        &self.channels
+    }
+
+    /// UNSUBSCRIBE from every channel and get the connection back
+    ///
+    /// Messages that arrive before the server confirms are discarded.
+    pub fn unsubscribe(mut self) -> Result<NubDB, NubError> {
+        let client = &mut self.client;
+        client.write_command("UNSUBSCRIBE")?;
+
+        // The server confirms each channel with the number still subscribed;
+        // zero means the connection is back to normal
+        loop {
+            let mut items = client.read_reply()?.into_array()?.into_iter();
+            let kind = items.next().map(Response::into_value).transpose()?.flatten();
+            if kind.as_deref() == Some("unsubscribe") {
+                let remaining = items.nth(1)
+                    .ok_or_else(|| NubError::UnexpectedResponse("unsubscribe without a count".to_string()))?
+                    .into_integer()?;
+                if remaining == 0 {
+                    return Ok(self.client);
+                }
+            }
+        }
+    }
+
+    /// Wait for the next message, or for the connection to be replaced
+    pub fn next_event(&mut self) -> Result<SubscriptionEvent, NubError> {
+        if !self.lost {
+            match self.next_message() {
+                Err(NubError::Io(_) | NubError::ConnectionClosed) if self.client.config.auto_reconnect => {
+                    self.lost = true;
+                }
+                result => return result.map(SubscriptionEvent::Message),
+            }
+        }
+
+        self.client.reconnect()?;
+        self.client.send_subscribe(&self.channels)?;
+        self.lost = false;
+        Ok(SubscriptionEvent::Reconnected)
+    }
+
+    /// Wait for the next message
+    fn next_message(&mut self) -> Result<Message, NubError> {
+        loop {
+            let mut items = self.client.read_reply()?.into_array()?.into_iter();
+            match (items.next(), items.next(), items.next()) {
+                (Some(Response::Simple(kind)), Some(channel), Some(payload)) if kind == "message" => {
+                    return Ok(Message {
+                        channel: channel.into_string()?,
+                        payload: payload.into_string()?,
+                    });
+                }
+                // Late subscribe confirmations and other pushes are skipped
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Message, NubError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_event() {
+                Ok(SubscriptionEvent::Message(message)) => return Some(Ok(message)),
+                Ok(SubscriptionEvent::Reconnected) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{break_socket, mock_node};
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_publish_subscribe() {
+        let subscriber = NubDB::connect("localhost:6379").unwrap();
+        let mut subscription = subscriber.subscribe(&["pubsub:a", "pubsub:b"]).unwrap();
+        assert_eq!(subscription.channels(), ["pubsub:a", "pubsub:b"]);
+
+        let mut publisher = NubDB::connect("localhost:6379").unwrap();
+        assert_eq!(publisher.publish("pubsub:b", "invalidate \"user:1\"").unwrap(), 1);
+        assert_eq!(publisher.publish("pubsub:none", "nobody listens").unwrap(), 0);
+
+        let message = subscription.next().unwrap().unwrap();
+        assert_eq!(message, Message {
+            channel: "pubsub:b".to_string(),
+            payload: "invalidate \"user:1\"".to_string(),
+        });
+
+        let mut client = subscription.unsubscribe().unwrap();
+        assert!(client.ping().is_ok());
+        assert_eq!(publisher.publish("pubsub:a", "gone").unwrap(), 0);
+
+        client.close().unwrap();
+        publisher.close().unwrap();
+    }
+
+    #[test]
+    fn test_subscription_resubscribes() {
+        // Every subscribe is confirmed and followed by one message
+        let (addr, seen) = mock_node(|cmd| match cmd {
+            "SUBSCRIBE a b" => "*3\nsubscribe\na\n1\n*3\nsubscribe\nb\n2\n*3\nmessage\nb\nhello".to_string(),
+            _ => "OK".to_string(),
+        });
+        let hello = Message { channel: "b".to_string(), payload: "hello".to_string() };
+
+        let client = NubDB::builder().auto_reconnect(true).connect(&addr).unwrap();
+        let mut subscription = client.subscribe(&["a", "b"]).unwrap();
+        assert_eq!(subscription.next_event().unwrap(), SubscriptionEvent::Message(hello.clone()));
+        break_socket(&subscription.client);
+        assert_eq!(subscription.next_event().unwrap(), SubscriptionEvent::Reconnected);
+        assert_eq!(subscription.next_event().unwrap(), SubscriptionEvent::Message(hello.clone()));
+
+        // Iteration skips the reconnect
+        break_socket(&subscription.client);
+        assert_eq!(subscription.next().unwrap().unwrap(), hello);
+        assert_eq!(*seen.lock().unwrap(), vec!["SUBSCRIBE a b"; 3]);
+
+        // Without auto_reconnect the subscription ends with the connection
+        let mut subscription = NubDB::connect(&addr).unwrap().subscribe(&["a", "b"]).unwrap();
+        subscription.next().unwrap().unwrap();
+        break_socket(&subscription.client);
+        assert!(subscription.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! How values are quoted on the command line
+
+use super::{escape_value, unescape_value, NubError, Response};
+
+/// How string values are written into commands, see
+/// [`NubDBBuilder::quote_style`]
+///
+/// Which style to pick depends on the server's command parser:
+///
+/// - `Double`, the default, suits current NubDB servers, which parse
+///   double-quoted arguments escaped as described in the crate docs.
+/// - `Single` suits server builds that parse single-quoted arguments
+///   instead. `'` and `\` are escaped with a backslash, as are newlines and
+///   carriage returns.
+/// - `None` suits the original whitespace-tokenizing parser, as in
+///   `src/protocol.zig`, which takes each word as it is and would store any
+///   quotes as part of the value. Values are sent bare, so only values
+///   without whitespace or control characters can be written; any other is
+///   rejected with `NubError::InvalidValue`. So is an empty value, one
+///   starting with a quote, and `(nil)`, none of which would read back as
+///   written.
+///
+/// The style applies to every value and member the blocking client sends,
+/// and is stripped again from values read back;
+/// [`NubDBBuilder::quote_style`] lists the few places that keep double
+/// quotes.
+///
+/// [`NubDBBuilder::quote_style`]: super::NubDBBuilder::quote_style
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuoteStyle {
+    #[default]
+    Double,
+    Single,
+    None,
+}
+
+impl QuoteStyle {
+    /// `value` as a command argument in this style
+    pub(crate) fn quote(self, value: &str) -> Result<String, NubError> {
+        match self {
+            QuoteStyle::Double => Ok(format!(r#""{}""#, escape_value(value))),
+            QuoteStyle::Single => {
+                let mut quoted = String::with_capacity(value.len() + 2);
+                quoted.push('\'');
+                for c in value.chars() {
+                    match c {
+                        '\'' => quoted.push_str("\\'"),
+                        '\\' => quoted.push_str("\\\\"),
+                        '\n' => quoted.push_str("\\n"),
+                        '\r' => quoted.push_str("\\r"),
+                        _ => quoted.push(c),
+                    }
+                }
+                quoted.push('\'');
+                Ok(quoted)
+            }
+            QuoteStyle::None => {
+                let representable = !value.is_empty()
+                    && !value.chars().any(|c| c.is_whitespace() || c.is_control())
+                    && !value.starts_with(['"', '\''])
+                    && value != "(nil)";
+                if !representable {
+                    return Err(NubError::InvalidValue(format!(
+                        "{:?} cannot be sent unquoted", value
+                    )));
+                }
+                Ok(value.to_string())
+            }
+        }
+    }
+
+    /// Read the single-quoted lines of a text reply, at any depth, as values
+    ///
+    /// Double-quoted lines are already values once parsed, but a
+    /// single-quoted one parses as a status reply with its quotes still on.
+    /// Under [`QuoteStyle::Single`] it becomes the value it quotes, so every
+    /// method and pipeline or transaction reply reads it back as written.
+    pub(crate) fn unquote_reply(self, response: Response) -> Response {
+        if self != QuoteStyle::Single {
+            return response;
+        }
+        match response {
+            Response::Simple(line) if is_single_quoted(&line) => Response::Bulk(Some(self.unquote(line))),
+            Response::Array(items) => Response::Array(items.into_iter().map(|item| self.unquote_reply(item)).collect()),
+            other => other,
+        }
+    }
+
+    /// Undo [`QuoteStyle::quote`] on a value the server sent back as written
+    ///
+    /// Double-quoted replies are already unquoted while parsing, so only
+    /// single quotes are left to strip here.
+    pub(crate) fn unquote(self, value: String) -> String {
+        if self != QuoteStyle::Single || !is_single_quoted(&value) {
+            return value;
+        }
+
+        let mut unquoted = String::with_capacity(value.len());
+        let mut chars = value[1..value.len() - 1].chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('\'')) => {
+                    unquoted.push('\'');
+                    chars.next();
+                }
+                ('\\', Some(next @ ('\\' | 'n' | 'r'))) => {
+                    unquoted.push_str(&unescape_value(&format!("\\{}", next)));
+                    chars.next();
+                }
+                _ => unquoted.push(c),
+            }
+        }
+        unquoted
+    }
+}
+
+fn is_single_quoted(value: &str) -> bool {
+    value.len() >= 2 && value.starts_with('\'') && value.ends_with('\'')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::tests::mock_node;
+    use crate::NubDB;
+
+    #[test]
+    fn test_quote_styles_round_trip() {
+        for value in ["plain", "two words", "it's", r#"say "hi""#, "back\\slash", "line\nbreak\r", ""] {
+            let quoted = QuoteStyle::Single.quote(value).unwrap();
+            assert_eq!(QuoteStyle::Single.unquote(quoted), value);
+            assert_eq!(QuoteStyle::Double.quote(value).unwrap(), format!(r#""{}""#, escape_value(value)));
+        }
+        assert_eq!(QuoteStyle::Single.quote("it's").unwrap(), r"'it\'s'");
+
+        assert_eq!(QuoteStyle::None.quote("plain").unwrap(), "plain");
+        for value in ["two words", "tab\there", "", "'quoted'", "\"quoted\"", "(nil)"] {
+            assert!(matches!(QuoteStyle::None.quote(value), Err(NubError::InvalidValue(_))), "{:?}", value);
+        }
+
+        // Other styles leave values that happen to look single-quoted alone
+        assert_eq!(QuoteStyle::Double.unquote("'kept'".to_string()), "'kept'");
+        assert_eq!(QuoteStyle::None.unquote("'kept'".to_string()), "'kept'");
+    }
+
+    #[test]
+    fn test_quote_style_on_the_wire() {
+        let (addr, seen) = mock_node(|cmd| match cmd {
+            "GET k" => r"'it\'s here'".to_string(),
+            _ => "OK".to_string(),
+        });
+        let mut client = NubDB::builder().quote_style(QuoteStyle::Single).connect(&addr).unwrap();
+        assert!(client.set("k", "it's here", Some(5)).unwrap());
+        assert_eq!(client.get("k").unwrap().as_deref(), Some("it's here"));
+
+        let mut bare = NubDB::builder().quote_style(QuoteStyle::None).connect(&addr).unwrap();
+        assert!(bare.set("k", "v", None).unwrap());
+        bare.mset(&[("a", "1"), ("b", "2")]).unwrap();
+        assert!(matches!(bare.set("k", "two words", None), Err(NubError::InvalidValue(_))));
+        assert!(matches!(bare.sadd("s", "two words"), Err(NubError::InvalidValue(_))));
+        assert!(matches!(bare.pipeline().set("k", "two words", None).execute(), Err(NubError::InvalidValue(_))));
+
+        assert_eq!(*seen.lock().unwrap(), vec![r"SET k 'it\'s here' 5", "GET k", "SET k v", "MSET a 1 b 2"]);
+    }
+
+    #[test]
+    fn test_quote_style_covers_every_value() {
+        let (addr, seen) = mock_node(|cmd| if cmd.starts_with("PING") { "'a b'".to_string() } else { "1".to_string() });
+        let mut client = NubDB::builder().quote_style(QuoteStyle::Single).connect(&addr).unwrap();
+        client.hset("h", "f", "a b").unwrap();
+        client.lpush("l", "a b").unwrap();
+        client.sadd("s", "a b").unwrap();
+        client.zadd("z", 1.0, "a b").unwrap();
+        client.set_range("k", 0, "a b").unwrap();
+        client.command(&["ECHO", "a b"]).unwrap();
+        client.ping_message("a b").unwrap();
+        client.pipeline().set("k", "a b", None).execute().unwrap();
+
+        assert_eq!(*seen.lock().unwrap(), vec![
+            "HSET h f 'a b'", "LPUSH l 'a b'", "SADD s 'a b'", "ZADD z 1 'a b'", "SETRANGE k 0 'a b'",
+            "ECHO 'a b'", "PING 'a b'", "SET k 'a b'",
+        ]);
+    }
+
+    #[test]
+    fn test_quote_style_strips_every_value_read() {
+        let (addr, _) = mock_node(|cmd| {
+            let reply = match cmd.split(' ').next().unwrap() {
+                "HGET" | "LPOP" | "RPOP" | "GET" => r"'it\'s'",
+                "HGETALL" => "*2\nf\n'a b'",
+                "BLPOP" => "*2\nl\n'a b'",
+                "LRANGE" | "SMEMBERS" => "*2\n'a b'\nplain",
+                "ZRANGE" | "ZPOPMIN" => "*2\n'a b'\n1",
+                "MULTI" => "OK",
+                "SET" => "QUEUED",
+                "EXEC" => "*1\n'a b'",
+                _ => "OK",
+            };
+            reply.to_string()
+        });
+        let mut client = NubDB::builder().quote_style(QuoteStyle::Single).connect(&addr).unwrap();
+        assert_eq!(client.hget("h", "f").unwrap().as_deref(), Some("it's"));
+        assert_eq!(client.hgetall("h").unwrap()["f"], "a b");
+        assert_eq!(client.lpop("l").unwrap().as_deref(), Some("it's"));
+        assert_eq!(client.rpop("l").unwrap().as_deref(), Some("it's"));
+        assert_eq!(client.blpop("l", Duration::from_secs(1)).unwrap(), Some(("l".to_string(), "a b".to_string())));
+        assert_eq!(client.lrange("l", 0, -1).unwrap(), vec!["a b", "plain"]);
+        assert!(client.smembers("s").unwrap().contains("a b"));
+        assert_eq!(client.zrange("z", 0, -1, true).unwrap(), vec![("a b".to_string(), Some(1.0))]);
+        assert_eq!(client.zpopmin("z", 1).unwrap(), vec![("a b".to_string(), 1.0)]);
+        assert_eq!(client.pipeline().get("k").execute().unwrap(), vec![Response::Bulk(Some("it's".to_string()))]);
+
+        let mut tx = client.multi().unwrap();
+        tx.set("k", "a b", None).unwrap();
+        assert_eq!(tx.exec().unwrap(), vec![Response::Bulk(Some("a b".to_string()))]);
+
+        // A value stored with its own quotes loses only the ones added to send it
+        let (addr, _) = mock_node(|_| r"'\'kept\''".to_string());
+        let mut client = NubDB::builder().quote_style(QuoteStyle::Single).connect(&addr).unwrap();
+        assert_eq!(client.get("k").unwrap().as_deref(), Some("'kept'"));
+    }
+}
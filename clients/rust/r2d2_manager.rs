@@ -0,0 +1,81 @@
+//! `r2d2` connection pool integration
+
+use super::{NubDB, NubDBBuilder, NubError};
+
+/// An [`r2d2::ManageConnection`] for [`NubDB`] connections
+///
+/// ```no_run
+/// # use nubdb::NubConnectionManager;
+/// let pool = r2d2::Pool::new(NubConnectionManager::new("localhost:6379"))?;
+/// let mut conn = pool.get()?;
+/// conn.set("key", "value", None)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// Connections are checked with a PING when handed out, so a socket the
+/// server has closed fails the check and is replaced. One whose last read or
+/// write failed is discarded as soon as it is returned to the pool.
+#[derive(Clone)]
+pub struct NubConnectionManager {
+    addr: String,
+    builder: NubDBBuilder,
+}
+
+impl NubConnectionManager {
+    /// Open pooled connections to `addr` with the default settings
+    pub fn new(addr: &str) -> Self {
+        Self::with_builder(addr, NubDB::builder())
+    }
+
+    /// Open pooled connections to `addr` with the settings in `builder`
+    pub fn with_builder(addr: &str, builder: NubDBBuilder) -> Self {
+        NubConnectionManager { addr: addr.to_string(), builder }
+    }
+}
+
+impl r2d2::ManageConnection for NubConnectionManager {
+    type Connection = NubDB;
+    type Error = NubError;
+
+    fn connect(&self) -> Result<NubDB, NubError> {
+        self.builder.clone().connect(&self.addr)
+    }
+
+    fn is_valid(&self, conn: &mut NubDB) -> Result<(), NubError> {
+        conn.ping().map(|_| ())
+    }
+
+    fn has_broken(&self, conn: &mut NubDB) -> bool {
+        conn.is_broken()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use r2d2::ManageConnection;
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_r2d2_pool() {
+        let pool = r2d2::Pool::builder()
+            .max_size(2)
+            .build(NubConnectionManager::new("localhost:6379"))
+            .unwrap();
+
+        let mut conn = pool.get().unwrap();
+        assert!(conn.set("r2d2:key", "value", None).unwrap());
+        assert_eq!(conn.get("r2d2:key").unwrap().as_deref(), Some("value"));
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_dead_socket_is_invalid() {
+        let manager = NubConnectionManager::new("localhost:6379");
+        let mut conn = manager.connect().unwrap();
+        assert!(manager.is_valid(&mut conn).is_ok());
+
+        conn.close().unwrap();
+        assert!(manager.is_valid(&mut conn).is_err());
+    }
+}
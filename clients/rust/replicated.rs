@@ -0,0 +1,262 @@
+//! Splitting reads and writes between a primary and its replicas
+
+use std::time::{Duration, Instant};
+
+use super::{retry, NubDB, NubDBBuilder, NubError};
+
+/// How long a failed replica is skipped before it is tried again
+const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A client that sends writes to a primary and spreads reads over replicas
+///
+/// Reads ([`get`](Self::get), [`exists`](Self::exists), [`mget`](Self::mget)
+/// and [`ttl`](Self::ttl)) go to the replicas in turn. Everything else goes
+/// to the primary, and commands without a wrapper here can be sent through
+/// [`primary`](Self::primary).
+///
+/// A replica that fails on the network is skipped for a while, 5 seconds by
+/// default, and then tried again on a later read. While no replica is
+/// available, reads go to the primary.
+///
+/// Replicas may lag behind the primary, so a read straight after a write can
+/// miss it. Use [`read_from_primary`](Self::read_from_primary) where that
+/// matters:
+///
+/// ```no_run
+/// # use nubdb::{NubError, ReplicatedNubDB};
+/// # fn main() -> Result<(), NubError> {
+/// let mut db = ReplicatedNubDB::connect("primary:6379", &["replica-1:6379", "replica-2:6379"])?;
+/// db.set("session:42", "active", None)?;
+///
+/// db.read_from_primary(true);
+/// assert_eq!(db.get("session:42")?.as_deref(), Some("active"));
+/// db.read_from_primary(false);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ReplicatedNubDB {
+    primary: NubDB,
+    replicas: Vec<Replica>,
+    builder: NubDBBuilder,
+    next: usize,
+    read_from_primary: bool,
+    retry_interval: Duration,
+}
+
+struct Replica {
+    addr: String,
+    conn: Option<NubDB>,
+    /// Set after a failure: skip this replica until then
+    down_until: Option<Instant>,
+}
+
+impl ReplicatedNubDB {
+    /// Connect to `primary` and `replicas`
+    ///
+    /// Fails if the primary cannot be reached. Replicas that cannot be
+    /// reached are skipped until their retry interval passes.
+    pub fn connect(primary: &str, replicas: &[&str]) -> Result<Self, NubError> {
+        Self::with_builder(primary, replicas, NubDB::builder())
+    }
+
+    /// Connect to the primary and replicas, opening every connection with `builder`
+    pub fn with_builder(primary: &str, replicas: &[&str], builder: NubDBBuilder) -> Result<Self, NubError> {
+        let mut db = ReplicatedNubDB {
+            primary: builder.clone().connect(primary)?,
+            replicas: replicas
+                .iter()
+                .map(|addr| Replica { addr: addr.to_string(), conn: None, down_until: None })
+                .collect(),
+            builder,
+            next: 0,
+            read_from_primary: false,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+        };
+        for i in 0..db.replicas.len() {
+            // Failures only mark the replica down
+            let _ = db.replica_conn(i);
+        }
+        Ok(db)
+    }
+
+    /// Send reads to the primary too, for read-after-write consistency
+    pub fn read_from_primary(&mut self, enabled: bool) {
+        self.read_from_primary = enabled;
+    }
+
+    /// How long a failed replica is skipped before it is tried again
+    pub fn retry_interval(&mut self, interval: Duration) {
+        self.retry_interval = interval;
+    }
+
+    /// The connection to the primary, for commands not wrapped here
+    pub fn primary(&mut self) -> &mut NubDB {
+        &mut self.primary
+    }
+
+    /// Number of replicas not currently skipped after a failure
+    pub fn available_replicas(&self) -> usize {
+        let now = Instant::now();
+        self.replicas.iter().filter(|replica| replica.is_available(now)).count()
+    }
+
+    /// SET key-value pair on the primary
+    pub fn set(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.primary.set(key, value, ttl)
+    }
+
+    /// DELETE key on the primary
+    pub fn delete(&mut self, key: &str) -> Result<bool, NubError> {
+        self.primary.delete(key)
+    }
+
+    /// INCR increment counter on the primary
+    pub fn incr(&mut self, key: &str) -> Result<i64, NubError> {
+        self.primary.incr(key)
+    }
+
+    /// DECR decrement counter on the primary
+    pub fn decr(&mut self, key: &str) -> Result<i64, NubError> {
+        self.primary.decr(key)
+    }
+
+    /// EXPIRE set a key's time to live on the primary
+    pub fn expire(&mut self, key: &str, seconds: u32) -> Result<bool, NubError> {
+        self.primary.expire(key, seconds)
+    }
+
+    /// MSET key-value pairs on the primary
+    pub fn mset(&mut self, pairs: &[(&str, &str)]) -> Result<bool, NubError> {
+        self.primary.mset(pairs)
+    }
+
+    /// DEL keys on the primary, returning how many existed
+    pub fn del_many(&mut self, keys: &[&str]) -> Result<usize, NubError> {
+        self.primary.del_many(keys)
+    }
+
+    /// GET value by key from a replica
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, NubError> {
+        self.read(|conn| conn.get(key))
+    }
+
+    /// EXISTS check if key exists, on a replica
+    pub fn exists(&mut self, key: &str) -> Result<bool, NubError> {
+        self.read(|conn| conn.exists(key))
+    }
+
+    /// MGET values for several keys from a replica
+    pub fn mget(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>, NubError> {
+        self.read(|conn| conn.mget(keys))
+    }
+
+    /// TTL remaining time to live, read from a replica
+    pub fn ttl(&mut self, key: &str) -> Result<Option<i64>, NubError> {
+        self.read(|conn| conn.ttl(key))
+    }
+
+    /// Run `op` on the next available replica, or on the primary if none is
+    ///
+    /// Replicas that fail on the network are marked down and the next one
+    /// is tried. Errors the server sent back are returned as they are.
+    fn read<T>(&mut self, mut op: impl FnMut(&mut NubDB) -> Result<T, NubError>) -> Result<T, NubError> {
+        if self.read_from_primary || self.replicas.is_empty() {
+            return op(&mut self.primary);
+        }
+
+        for _ in 0..self.replicas.len() {
+            let i = self.next;
+            self.next = (self.next + 1) % self.replicas.len();
+
+            let Ok(conn) = self.replica_conn(i) else { continue };
+            match op(conn) {
+                Err(e) if retry::is_transient(&e) || conn.is_broken() => self.mark_down(i),
+                result => return result,
+            }
+        }
+        op(&mut self.primary)
+    }
+
+    /// The connection to replica `i`, connecting if it is due a retry
+    fn replica_conn(&mut self, i: usize) -> Result<&mut NubDB, NubError> {
+        let replica = &mut self.replicas[i];
+        if !replica.is_available(Instant::now()) {
+            return Err(NubError::Protocol(format!("replica {} is down", replica.addr)));
+        }
+
+        if replica.conn.is_none() {
+            match self.builder.clone().connect(&replica.addr) {
+                Ok(conn) => replica.conn = Some(conn),
+                Err(e) => {
+                    self.mark_down(i);
+                    return Err(e);
+                }
+            }
+        }
+        replica.down_until = None;
+        Ok(self.replicas[i].conn.as_mut().expect("replica was just connected"))
+    }
+
+    fn mark_down(&mut self, i: usize) {
+        let replica = &mut self.replicas[i];
+        replica.conn = None;
+        replica.down_until = Some(Instant::now() + self.retry_interval);
+    }
+}
+
+impl Replica {
+    fn is_available(&self, now: Instant) -> bool {
+        self.down_until.is_none_or(|until| now >= until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::mock_node;
+
+    fn named_node(name: &'static str) -> (String, std::sync::Arc<std::sync::Mutex<Vec<String>>>) {
+        mock_node(move |cmd| if cmd.starts_with("GET") { format!("\"{}\"", name) } else { "OK".to_string() })
+    }
+
+    #[test]
+    fn test_reads_round_robin_and_writes_go_to_primary() {
+        let (primary, primary_seen) = named_node("primary");
+        let (a, _) = named_node("a");
+        let (b, _) = named_node("b");
+
+        let mut db = ReplicatedNubDB::connect(&primary, &[&a, &b]).unwrap();
+        let reads: Vec<_> = (0..4).map(|_| db.get("key").unwrap().unwrap()).collect();
+        assert_eq!(reads, ["a", "b", "a", "b"]);
+
+        assert!(db.set("key", "value", None).unwrap());
+        assert_eq!(primary_seen.lock().unwrap().as_slice(), [r#"SET key "value""#]);
+
+        db.read_from_primary(true);
+        assert_eq!(db.get("key").unwrap().as_deref(), Some("primary"));
+    }
+
+    #[test]
+    fn test_dead_replicas_are_skipped() {
+        let (primary, _) = named_node("primary");
+        let (live, _) = named_node("live");
+        let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+        let mut db = ReplicatedNubDB::connect(&primary, &[&dead, &live]).unwrap();
+        assert_eq!(db.available_replicas(), 1);
+        for _ in 0..3 {
+            assert_eq!(db.get("key").unwrap().as_deref(), Some("live"));
+        }
+
+        // With every replica down, reads fall back to the primary
+        let mut db = ReplicatedNubDB::connect(&primary, &[&dead]).unwrap();
+        assert_eq!(db.get("key").unwrap().as_deref(), Some("primary"));
+
+        // Down replicas are tried again once the interval has passed
+        db.retry_interval(Duration::ZERO);
+        db.mark_down(0);
+        assert_eq!(db.available_replicas(), 1);
+        assert_eq!(db.get("key").unwrap().as_deref(), Some("primary"));
+        assert!(db.replicas[0].down_until.is_some());
+    }
+}
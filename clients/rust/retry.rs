@@ -0,0 +1,178 @@
+//! Retrying commands after transient failures
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::time::Duration;
+
+use super::NubError;
+
+/// Commands that only read, and so are always safe to send again
+const READ_COMMANDS: &[&str] = &[
+    "EXISTS", "GET", "HGET", "HGETALL", "INFO", "KEYS", "LRANGE", "MGET", "PING", "PTTL",
+    "RANDOMKEY", "SCAN", "SISMEMBER", "SIZE", "SMEMBERS", "STRLEN", "TTL", "TYPE",
+];
+
+/// When and how often to retry a command that failed on the network
+///
+/// Set with [`NubDBBuilder::retry`]. A command is retried when it fails with
+/// `NubError::Timeout` or an I/O error such as a reset or refused connection;
+/// the client reconnects before each retry. Errors the server sent back, such
+/// as a wrong type or a rejected argument, are never retried.
+///
+/// Only read commands are retried unless [`RetryPolicy::retry_writes`] is
+/// set: a write whose reply was lost may already have been applied, so
+/// sending it again can apply it twice.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use nubdb::RetryPolicy;
+/// // Up to 4 attempts, waiting 50ms, 100ms and 200ms (each +/- 20%) in between
+/// let policy = RetryPolicy::new(4)
+///     .base_delay(Duration::from_millis(50))
+///     .multiplier(2.0)
+///     .jitter(0.2);
+/// ```
+///
+/// [`NubDBBuilder::retry`]: super::NubDBBuilder::retry
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    multiplier: f64,
+    jitter: f64,
+    retry_writes: bool,
+}
+
+impl Default for RetryPolicy {
+    /// 3 attempts, starting at 100ms and doubling, with 10% jitter
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            jitter: 0.1,
+            retry_writes: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Try each command at most `max_attempts` times, counting the first
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy { max_attempts: max_attempts.max(1), ..Self::default() }
+    }
+
+    /// Wait this long before the first retry
+    pub fn base_delay(mut self, delay: Duration) -> Self {
+        self.base_delay = delay;
+        self
+    }
+
+    /// Multiply the wait by `multiplier` after each retry
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Vary each wait randomly by up to this fraction, from 0.0 to 1.0
+    ///
+    /// Keeps clients that failed together from retrying in lockstep.
+    pub fn jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Retry writes as well as reads
+    pub fn retry_writes(mut self, enabled: bool) -> Self {
+        self.retry_writes = enabled;
+        self
+    }
+
+    pub(crate) fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// Whether `cmd` may be retried under this policy
+    pub(crate) fn applies_to(&self, cmd: &str) -> bool {
+        self.retry_writes || is_read_command(cmd)
+    }
+
+    /// How long to wait after failed attempt number `attempt`, counting from 1
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.base_delay.as_secs_f64() * self.multiplier.powi(exponent);
+        // Uniform in [1 - jitter, 1 + jitter]
+        let spread = 1.0 + self.jitter * (2.0 * random_fraction() - 1.0);
+        Duration::try_from_secs_f64(delay * spread).unwrap_or(Duration::MAX)
+    }
+}
+
+/// Whether `err` is a network failure that may succeed on a new connection
+pub(crate) fn is_transient(err: &NubError) -> bool {
+    match err {
+        NubError::Timeout | NubError::ConnectionClosed => true,
+        NubError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::Interrupted
+        ),
+        _ => false,
+    }
+}
+
+fn is_read_command(cmd: &str) -> bool {
+    let name = cmd.split(' ').next().unwrap_or("");
+    READ_COMMANDS.iter().any(|read| read.eq_ignore_ascii_case(name))
+}
+
+/// A random number in `[0, 1)`, good enough for spreading out retries
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_backs_off() {
+        let policy = RetryPolicy::new(5)
+            .base_delay(Duration::from_millis(100))
+            .multiplier(2.0)
+            .jitter(0.0);
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(400));
+
+        let jittered = policy.jitter(0.5);
+        for _ in 0..20 {
+            let delay = jittered.delay(2);
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(300), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_only_reads_retry_by_default() {
+        let policy = RetryPolicy::default();
+        assert!(policy.applies_to("GET key"));
+        assert!(policy.applies_to("mget a b"));
+        assert!(!policy.applies_to(r#"SET key "value""#));
+        assert!(!policy.applies_to("INCR counter"));
+        assert!(policy.retry_writes(true).applies_to("INCR counter"));
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        assert!(is_transient(&NubError::Timeout));
+        assert!(is_transient(&NubError::Io(io::ErrorKind::ConnectionReset.into())));
+        assert!(!is_transient(&NubError::Io(io::ErrorKind::PermissionDenied.into())));
+        assert!(!is_transient(&NubError::Protocol("WRONGTYPE".to_string())));
+    }
+}
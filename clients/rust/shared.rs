@@ -0,0 +1,264 @@
+//! A single connection shared between threads
+
+use std::ops::{Deref, DerefMut};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{NubDB, NubError};
+
+/// A cloneable, thread-safe handle to one [`NubDB`] connection
+///
+/// Every clone talks to the same socket. Each command holds an internal lock
+/// from the moment its request is written until its reply has been read, so
+/// commands from different threads never read each other's replies.
+///
+/// Because only one command is in flight at a time, throughput is bounded by
+/// the round-trip latency of that one connection: threads queue behind each
+/// other on the lock. When many threads issue commands concurrently, a
+/// [`NubPool`](super::NubPool) lets them run in parallel over several
+/// connections instead.
+#[derive(Clone)]
+pub struct SharedNubDB {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    conn: NubDB,
+    /// When the last command, or keepalive PING, finished
+    last_used: Instant,
+}
+
+/// The connection, locked for one command; finishing it counts as activity
+/// for [`SharedNubDB::keepalive`]
+struct Locked<'a>(MutexGuard<'a, Inner>);
+
+/// The thread started by [`SharedNubDB::keepalive`], stopped when this is
+/// dropped
+pub struct KeepAlive {
+    /// Never sent on: dropping it wakes the thread and ends it
+    _stop: Sender<()>,
+}
+
+impl SharedNubDB {
+    /// Connect to NubDB server
+    pub fn connect(addr: &str) -> Result<Self, NubError> {
+        Ok(Self::new(NubDB::connect(addr)?))
+    }
+
+    /// Share an existing connection
+    pub fn new(client: NubDB) -> Self {
+        SharedNubDB { inner: Arc::new(Mutex::new(Inner { conn: client, last_used: Instant::now() })) }
+    }
+
+    /// Run several commands without other threads interleaving
+    pub fn with<T>(&self, f: impl FnOnce(&mut NubDB) -> T) -> T {
+        f(&mut self.lock())
+    }
+
+    /// PING the server from a background thread whenever the connection has
+    /// been idle for `interval`, so a server idle timeout never closes it
+    ///
+    /// The PING takes the same lock as every other command, so it never
+    /// lands between a command and its reply, and a command in flight just
+    /// makes it wait. Any command counts as activity: a connection in steady
+    /// use is never pinged. A failed PING is ignored; it leaves the
+    /// connection broken, and the next command reconnects or fails as it
+    /// would have without the keepalive.
+    ///
+    /// A transaction must be run inside one [`SharedNubDB::with`] call, as
+    /// that holds the lock from `MULTI` to `EXEC`; otherwise a PING can be
+    /// queued in it. A watch set with [`NubDB::watch`] survives PINGs, which
+    /// write nothing. [`NubDB::subscribe`] needs a connection of its own,
+    /// which this cannot reach, and a subscribed connection is expected to
+    /// sit idle anyway.
+    ///
+    /// The thread stops when the returned [`KeepAlive`] is dropped, or once
+    /// every clone of this client has been dropped.
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use nubdb::SharedNubDB;
+    /// let client = SharedNubDB::connect("localhost:6379")?;
+    /// let _keepalive = client.keepalive(Duration::from_secs(30));
+    /// # Ok::<(), nubdb::NubError>(())
+    /// ```
+    pub fn keepalive(&self, interval: Duration) -> KeepAlive {
+        let (stop, stopped) = mpsc::channel();
+        let inner = Arc::downgrade(&self.inner);
+        thread::spawn(move || {
+            let mut wait = interval;
+            // A disconnected channel means the KeepAlive was dropped too
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                match ping_if_idle(&inner, interval) {
+                    Some(next) => wait = next,
+                    None => return,
+                }
+            }
+        });
+        KeepAlive { _stop: stop }
+    }
+
+    fn lock(&self) -> Locked<'_> {
+        Locked(lock(&self.inner))
+    }
+
+    /// SET key-value pair
+    pub fn set(&self, key: &str, value: &str, ttl: Option<u32>) -> Result<bool, NubError> {
+        self.lock().set(key, value, ttl)
+    }
+
+    /// MSET several key-value pairs in one round trip
+    pub fn mset(&self, pairs: &[(&str, &str)]) -> Result<bool, NubError> {
+        self.lock().mset(pairs)
+    }
+
+    /// GET value by key
+    pub fn get(&self, key: &str) -> Result<Option<String>, NubError> {
+        self.lock().get(key)
+    }
+
+    /// MGET values for several keys in one round trip
+    pub fn mget(&self, keys: &[&str]) -> Result<Vec<Option<String>>, NubError> {
+        self.lock().mget(keys)
+    }
+
+    /// DELETE key
+    pub fn delete(&self, key: &str) -> Result<bool, NubError> {
+        self.lock().delete(key)
+    }
+
+    /// EXISTS check if key exists
+    pub fn exists(&self, key: &str) -> Result<bool, NubError> {
+        self.lock().exists(key)
+    }
+
+    /// INCR increment counter
+    pub fn incr(&self, key: &str) -> Result<i64, NubError> {
+        self.lock().incr(key)
+    }
+
+    /// DECR decrement counter
+    pub fn decr(&self, key: &str) -> Result<i64, NubError> {
+        self.lock().decr(key)
+    }
+
+    /// INCRBY increment counter by `amount`
+    pub fn incr_by(&self, key: &str, amount: i64) -> Result<i64, NubError> {
+        self.lock().incr_by(key, amount)
+    }
+
+    /// DECRBY decrement counter by `amount`
+    pub fn decr_by(&self, key: &str, amount: i64) -> Result<i64, NubError> {
+        self.lock().decr_by(key, amount)
+    }
+
+    /// EXPIRE set a key's time to live in seconds
+    pub fn expire(&self, key: &str, seconds: u32) -> Result<bool, NubError> {
+        self.lock().expire(key, seconds)
+    }
+
+    /// TTL get the remaining time to live of a key in seconds
+    pub fn ttl(&self, key: &str) -> Result<Option<i64>, NubError> {
+        self.lock().ttl(key)
+    }
+
+    /// SIZE get number of keys
+    pub fn size(&self) -> Result<usize, NubError> {
+        self.lock().size()
+    }
+}
+
+fn lock(inner: &Mutex<Inner>) -> MutexGuard<'_, Inner> {
+    // A panic in another thread mid-command leaves the connection broken
+    // rather than unsafe to touch; later commands surface the I/O error.
+    inner.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// PING if the connection has been idle for `interval`, returning how long
+/// to wait before looking again, or `None` once the client is gone
+fn ping_if_idle(inner: &Weak<Mutex<Inner>>, interval: Duration) -> Option<Duration> {
+    let inner = inner.upgrade()?;
+    let mut inner = lock(&inner);
+    let idle = inner.last_used.elapsed();
+    if idle < interval {
+        return Some(interval - idle);
+    }
+    let _ = inner.conn.ping();
+    inner.last_used = Instant::now();
+    Some(interval)
+}
+
+impl Deref for Locked<'_> {
+    type Target = NubDB;
+
+    fn deref(&self) -> &NubDB {
+        &self.0.conn
+    }
+}
+
+impl DerefMut for Locked<'_> {
+    fn deref_mut(&mut self) -> &mut NubDB {
+        &mut self.0.conn
+    }
+}
+
+impl Drop for Locked<'_> {
+    fn drop(&mut self) {
+        self.0.last_used = Instant::now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::mock_node;
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_threads_never_see_each_others_replies() {
+        let client = SharedNubDB::connect("localhost:6379").unwrap();
+
+        let handles: Vec<_> = (0..4)
+            .map(|t| {
+                let client = client.clone();
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let key = format!("shared:{}:{}", t, i);
+                        let value = format!("{}-{}", t, i);
+                        assert!(client.set(&key, &value, None).unwrap());
+                        assert_eq!(client.get(&key).unwrap(), Some(value));
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+    #[test]
+    fn test_keepalive_pings_only_when_idle() {
+        let (addr, seen) = mock_node(|cmd| if cmd == "PING" { "PONG".to_string() } else { "OK".to_string() });
+        let client = SharedNubDB::new(NubDB::connect(&addr).unwrap());
+        let pings = || seen.lock().unwrap().iter().filter(|cmd| *cmd == "PING").count();
+
+        let keepalive = client.keepalive(Duration::from_millis(50));
+        thread::sleep(Duration::from_millis(180));
+        let idle_pings = pings();
+        assert!(idle_pings >= 2, "{} pings while idle", idle_pings);
+
+        // Commands more often than the interval keep it from pinging
+        for _ in 0..15 {
+            client.set("k", "v", None).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(pings() <= idle_pings + 1);
+
+        drop(keepalive);
+        let stopped = pings();
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(pings(), stopped);
+    }
+}
@@ -0,0 +1,198 @@
+//! Transports the blocking client can talk over
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use super::NubError;
+
+/// A byte stream a [`NubDB`](super::NubDB) client can talk over instead of
+/// a socket it opened itself, see [`NubDBBuilder::connect_transport`]
+///
+/// Commands are written to it and replies read from it exactly as they
+/// would be on a TCP connection, which lets tests script a server's side of
+/// the conversation without a network, or a downstream crate tunnel the
+/// protocol through something else. A `write` may take only part of what
+/// it is given; the client keeps writing until the whole command is sent.
+/// The provided methods do nothing, for transports without a socket
+/// underneath.
+///
+/// [`NubDBBuilder::connect_transport`]: super::NubDBBuilder::connect_transport
+pub trait Transport: Read + Write + Send {
+    /// Fail reads that wait longer than `timeout` with
+    /// `io::ErrorKind::TimedOut`, or never if `None`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        let _ = timeout;
+        Ok(())
+    }
+
+    /// Close the transport, called by [`NubDB::close`](super::NubDB::close)
+    /// and when the client is dropped
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The socket underneath a [`NubDB`](super::NubDB) connection
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Custom(Box<dyn Transport>),
+    /// Nothing opened yet, for a client built with
+    /// [`NubDBBuilder::lazy`](super::NubDBBuilder::lazy)
+    Pending,
+}
+
+impl Stream {
+    pub(crate) fn set_nodelay(&self, enabled: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(enabled),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock.set_nodelay(enabled),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+            Stream::Custom(_) | Stream::Pending => Ok(()),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            Stream::Custom(transport) => transport.set_read_timeout(timeout),
+            Stream::Pending => Ok(()),
+        }
+    }
+
+    /// Shut down both directions of the socket
+    ///
+    /// A TLS stream sends `close_notify` first. That write is best effort,
+    /// since the server may already have closed its end after `QUIT`.
+    pub(crate) fn shutdown(&mut self) -> io::Result<()> {
+        let result = match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => {
+                stream.conn.send_close_notify();
+                let _ = stream.flush();
+                stream.sock.shutdown(Shutdown::Both)
+            }
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Both),
+            Stream::Custom(transport) => transport.shutdown(),
+            Stream::Pending => Ok(()),
+        };
+        match result {
+            // The peer got there first
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            result => result,
+        }
+    }
+
+    /// A second handle on the socket, to shut it down from another thread
+    /// while this one is blocked reading it
+    ///
+    /// A TLS stream's handle is its TCP socket, so shutting it down sends no
+    /// `close_notify`.
+    pub(crate) fn try_clone_socket(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            Stream::Custom(_) | Stream::Pending => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, "no socket to share"))
+            }
+        }
+    }
+
+    /// Wrap a connected socket in TLS and complete the handshake
+    ///
+    /// Handshaking eagerly makes certificate problems fail the connect call
+    /// rather than the first command.
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls(
+        mut tcp: TcpStream,
+        host: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<Stream, NubError> {
+        let server_name = rustls::pki_types::ServerName::try_from(host.to_string())
+            .map_err(|e| NubError::Tls(format!("invalid server name {:?}: {}", host, e)))?;
+        let mut conn = rustls::ClientConnection::new(config, server_name)
+            .map_err(|e| NubError::Tls(e.to_string()))?;
+
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)?;
+        }
+
+        Ok(Stream::Tls(Box::new(rustls::StreamOwned::new(conn, tcp))))
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Custom(transport) => transport.read(buf),
+            Stream::Pending => Err(not_connected()),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Custom(transport) => transport.write(buf),
+            Stream::Pending => Err(not_connected()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Custom(transport) => transport.flush(),
+            Stream::Pending => Err(not_connected()),
+        }
+    }
+}
+
+fn not_connected() -> io::Error {
+    io::Error::new(io::ErrorKind::NotConnected, "not connected yet")
+}
+
+/// The host part of a `host:port` or `[v6]:port` address, for TLS server names
+#[cfg(feature = "tls")]
+pub(crate) fn host_of(addr: &str) -> &str {
+    if let Some(rest) = addr.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match addr.rsplit_once(':') {
+        Some((host, _port)) => host,
+        None => addr,
+    }
+}
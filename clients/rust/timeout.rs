@@ -0,0 +1,217 @@
+//! Read timeouts for a single call
+
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+use super::{NubDB, NubError};
+
+/// A client with its read timeout overridden, see [`NubDB::with_timeout`]
+///
+/// Dereferences to [`NubDB`]. Dropping the guard puts the previous read
+/// timeout back, whether or not the commands sent through it succeeded.
+pub struct WithTimeout<'a> {
+    client: &'a mut NubDB,
+    previous: Option<Duration>,
+}
+
+/// A client bound to a deadline, see [`NubDB::with_deadline`]
+///
+/// Dereferences to [`NubDB`]. Dropping the guard lifts the deadline and puts
+/// the previous read timeout back.
+pub struct WithDeadline<'a> {
+    client: &'a mut NubDB,
+    previous: Option<Instant>,
+}
+
+impl NubDB {
+    /// Use `timeout` as the read timeout until the returned guard is dropped
+    ///
+    /// Meant to be used for one call, the guard being dropped at the end of
+    /// the statement:
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use nubdb::NubDB;
+    /// let mut client = NubDB::builder()
+    ///     .read_timeout(Duration::from_millis(200))
+    ///     .connect("localhost:6379")?;
+    /// let report = client.with_timeout(Duration::from_secs(30))?.get("report:daily")?;
+    /// # Ok::<(), nubdb::NubError>(())
+    /// ```
+    ///
+    /// The override also applies to a socket opened by a reconnect during the
+    /// call. Blocking commands such as [`NubDB::blpop`] still lift it if it is
+    /// shorter than their wait. A zero `timeout` is `NubError::InvalidArgument`.
+    pub fn with_timeout(&mut self, timeout: Duration) -> Result<WithTimeout<'_>, NubError> {
+        if timeout.is_zero() {
+            return Err(NubError::InvalidArgument("read timeout must be longer than zero".to_string()));
+        }
+
+        self.stream.get_ref().set_read_timeout(Some(timeout))?;
+        let previous = self.config.read_timeout.replace(timeout);
+        Ok(WithTimeout { client: self, previous })
+    }
+
+    /// Finish every command by `deadline` until the returned guard is dropped
+    ///
+    /// Unlike [`NubDB::with_timeout`], the time allowed shrinks as commands
+    /// are sent: each one waits for its reply for at most the time left
+    /// until `deadline`, or the configured read timeout if that is shorter.
+    /// A command sent once the deadline has passed fails with
+    /// `NubError::Timeout` without being sent, as does a deadline that has
+    /// already passed when the guard is made.
+    ///
+    /// ```no_run
+    /// # use std::time::{Duration, Instant};
+    /// # use nubdb::NubDB;
+    /// # let mut client = NubDB::connect("localhost:6379")?;
+    /// let deadline = Instant::now() + Duration::from_millis(250);
+    /// let mut scoped = client.with_deadline(deadline)?;
+    /// let user = scoped.get("user:1")?;
+    /// let visits = scoped.incr("visits:user:1")?;
+    /// # Ok::<(), nubdb::NubError>(())
+    /// ```
+    ///
+    /// The deadline bounds waiting for replies, including those of blocking
+    /// commands such as [`NubDB::blpop`]; opening a connection is still bound
+    /// by the connect timeout alone.
+    pub fn with_deadline(&mut self, deadline: Instant) -> Result<WithDeadline<'_>, NubError> {
+        let previous = self.deadline.replace(deadline);
+        let guard = WithDeadline { client: self, previous };
+        guard.client.apply_deadline()?;
+        Ok(guard)
+    }
+
+    /// Shorten the socket's read timeout to the time left until the
+    /// deadline, if there is one
+    pub(crate) fn apply_deadline(&mut self) -> Result<(), NubError> {
+        let Some(deadline) = self.deadline else {
+            return Ok(());
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(NubError::Timeout);
+        }
+
+        let timeout = self.config.read_timeout.map_or(remaining, |timeout| timeout.min(remaining));
+        self.stream.get_ref().set_read_timeout(Some(timeout))?;
+        Ok(())
+    }
+}
+
+impl Deref for WithTimeout<'_> {
+    type Target = NubDB;
+
+    fn deref(&self) -> &NubDB {
+        self.client
+    }
+}
+
+impl DerefMut for WithTimeout<'_> {
+    fn deref_mut(&mut self) -> &mut NubDB {
+        self.client
+    }
+}
+
+impl Drop for WithTimeout<'_> {
+    fn drop(&mut self) {
+        self.client.config.read_timeout = self.previous;
+        // Only fails if the socket is gone, and a reconnect applies the setting
+        let _ = self.client.stream.get_ref().set_read_timeout(self.previous);
+    }
+}
+
+impl Deref for WithDeadline<'_> {
+    type Target = NubDB;
+
+    fn deref(&self) -> &NubDB {
+        self.client
+    }
+}
+
+impl DerefMut for WithDeadline<'_> {
+    fn deref_mut(&mut self) -> &mut NubDB {
+        self.client
+    }
+}
+
+impl Drop for WithDeadline<'_> {
+    fn drop(&mut self) {
+        self.client.deadline = self.previous;
+        let _ = self.client.stream.get_ref().set_read_timeout(self.client.config.read_timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::Stream;
+    use crate::tests::mock_node;
+
+    fn socket_timeout(client: &NubDB) -> Option<Duration> {
+        match client.stream.get_ref() {
+            Stream::Tcp(stream) => stream.read_timeout().unwrap(),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!("test clients use TCP"),
+        }
+    }
+
+    #[test]
+    fn test_with_timeout_restores_previous() {
+        let (addr, _) = mock_node(|cmd| {
+            if cmd == "GET slow" {
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            r#""v""#.to_string()
+        });
+
+        let short = Duration::from_millis(50);
+        let mut client = NubDB::builder().read_timeout(short).connect(&addr).unwrap();
+        assert_eq!(client.with_timeout(Duration::from_secs(2)).unwrap().get("slow").unwrap().as_deref(), Some("v"));
+        // The kernel rounds timeouts to its tick
+        assert!(socket_timeout(&client).is_some_and(|timeout| timeout < Duration::from_secs(1)));
+        assert_eq!(client.config.read_timeout, Some(short));
+        assert!(matches!(client.get("slow"), Err(NubError::Timeout)));
+
+        // Restored after a failed call too
+        let mut client = NubDB::connect(&addr).unwrap();
+        assert!(matches!(client.with_timeout(short).unwrap().get("slow"), Err(NubError::Timeout)));
+        assert_eq!(socket_timeout(&client), None);
+        assert_eq!(client.config.read_timeout, None);
+
+        assert!(matches!(client.with_timeout(Duration::ZERO), Err(NubError::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn test_with_deadline_shrinks_timeout() {
+        let (addr, seen) = mock_node(|cmd| {
+            if cmd == "GET slow" {
+                std::thread::sleep(Duration::from_millis(150));
+            }
+            r#""v""#.to_string()
+        });
+
+        let mut client = NubDB::builder().read_timeout(Duration::from_secs(5)).connect(&addr).unwrap();
+        {
+            let mut scoped = client.with_deadline(Instant::now() + Duration::from_millis(250)).unwrap();
+            assert_eq!(scoped.get("slow").unwrap().as_deref(), Some("v"));
+            // Only about 100ms are left for the second one
+            assert!(matches!(scoped.get("slow"), Err(NubError::Timeout)));
+        }
+        assert!(socket_timeout(&client).is_some_and(|timeout| timeout > Duration::from_secs(4)));
+
+        let mut client = NubDB::connect(&addr).unwrap();
+        {
+            let mut scoped = client.with_deadline(Instant::now() + Duration::from_millis(50)).unwrap();
+            assert!(socket_timeout(&scoped).is_some());
+            std::thread::sleep(Duration::from_millis(60));
+            assert!(matches!(scoped.get("fast"), Err(NubError::Timeout)));
+        }
+        assert_eq!(socket_timeout(&client), None);
+        assert_eq!(client.get("fast").unwrap().as_deref(), Some("v"));
+        assert!(matches!(client.with_deadline(Instant::now()), Err(NubError::Timeout)));
+
+        // The expired deadline kept the first GET fast from being sent
+        assert_eq!(*seen.lock().unwrap(), vec!["GET slow", "GET slow", "GET fast"]);
+    }
+}
@@ -0,0 +1,72 @@
+//! `tracing` spans around commands
+
+use std::time::Duration;
+
+use tracing::field::Empty;
+use tracing::Span;
+
+use super::{retry, NubError, Response};
+
+/// Commands whose first argument is not a key, or must never be recorded
+const KEYLESS_COMMANDS: &[&str] = &["AUTH", "INFO", "PING", "SCAN", "SELECT"];
+
+/// Open the span for one command
+///
+/// The key is only recorded when `with_key` is set, since per-key values
+/// make for unbounded cardinality in most tracing backends.
+pub(crate) fn command_span(name: &str, cmd: &str, with_key: bool) -> Span {
+    let span = tracing::info_span!(
+        "nubdb.command",
+        command = name,
+        key = Empty,
+        duration_us = Empty,
+        outcome = Empty,
+    );
+    if with_key {
+        if let Some(key) = command_key(name, cmd) {
+            span.record("key", key);
+        }
+    }
+    span
+}
+
+/// Record the outcome on `span` and log failures
+///
+/// Network failures are logged as warnings; errors the server sent back are
+/// usually the caller's to handle and are logged at debug level.
+pub(crate) fn record(span: &Span, duration: Duration, result: &Result<Response, NubError>) {
+    span.record("duration_us", duration.as_micros() as u64);
+    match result {
+        Ok(_) => {
+            span.record("outcome", "ok");
+        }
+        Err(e) => {
+            span.record("outcome", "error");
+            if retry::is_transient(e) {
+                tracing::warn!(parent: span, error = %e, "command failed");
+            } else {
+                tracing::debug!(parent: span, error = %e, "command failed");
+            }
+        }
+    }
+}
+
+fn command_key<'a>(name: &str, cmd: &'a str) -> Option<&'a str> {
+    if KEYLESS_COMMANDS.iter().any(|keyless| keyless.eq_ignore_ascii_case(name)) {
+        return None;
+    }
+    cmd.split(' ').nth(1).filter(|key| !key.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_key() {
+        assert_eq!(command_key("GET", "GET user:1"), Some("user:1"));
+        assert_eq!(command_key("SET", r#"SET user:1 "value""#), Some("user:1"));
+        assert_eq!(command_key("SIZE", "SIZE"), None);
+        assert_eq!(command_key("AUTH", r#"AUTH "secret""#), None);
+    }
+}
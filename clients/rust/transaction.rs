@@ -0,0 +1,314 @@
+//! MULTI/EXEC transactions
+
+use super::{set_command, NubDB, NubError, Response};
+
+/// Commands applied atomically by the server
+///
+/// Created by [`NubDB::multi`], which sends `MULTI`. Each queued command is
+/// sent straight away and acknowledged by the server with `QUEUED`, but only
+/// runs once [`Transaction::exec`] sends `EXEC`; [`Transaction::discard`]
+/// sends `DISCARD` and throws the queued commands away. Dropping a
+/// transaction without calling either discards it.
+///
+/// A command the server rejects while queueing is returned as an error, and
+/// the server will then refuse to execute the transaction.
+///
+/// For a transaction that depends on values read beforehand, watch the keys
+/// with [`NubDB::watch`] first: if any of them is written before `EXEC`, the
+/// transaction runs nothing and can be retried with fresh values.
+///
+/// ```no_run
+/// # use nubdb::NubDB;
+/// # let mut client = NubDB::connect("localhost:6379")?;
+/// loop {
+///     client.watch(&["stock:apples"])?;
+///     let stock: i64 = client.get_typed("stock:apples")?.unwrap_or(0);
+///     let mut tx = client.multi()?;
+///     tx.set("stock:apples", &(stock - 1).to_string(), None)?;
+///     if tx.try_exec()?.is_some() {
+///         break;
+///     }
+/// }
+/// # Ok::<(), nubdb::NubError>(())
+/// ```
+pub struct Transaction<'a> {
+    client: &'a mut NubDB,
+    queued: usize,
+    finished: bool,
+}
+
+/// The keys watched with WATCH, as the client tracks them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Watch {
+    Off,
+    Active,
+    /// Watched on a connection that has since been replaced, so the server
+    /// no longer guards them
+    Lost,
+}
+
+impl NubDB {
+    /// WATCH `keys`, so that the next transaction runs only if none of them
+    /// is written first
+    ///
+    /// The watch lasts until the next [`Transaction::exec`] or
+    /// [`Transaction::discard`], or [`NubDB::unwatch`], whether or not a
+    /// watched key was written. A reconnect in between, for example by
+    /// [`NubDBBuilder::auto_reconnect`], loses it on the server; the client
+    /// remembers, and aborts the transaction instead of running it unguarded.
+    ///
+    /// [`NubDBBuilder::auto_reconnect`]: super::NubDBBuilder::auto_reconnect
+    pub fn watch(&mut self, keys: &[&str]) -> Result<(), NubError> {
+        if keys.is_empty() {
+            return Err(NubError::InvalidArgument("no keys to watch".to_string()));
+        }
+        self.config.check_keys(keys)?;
+        self.expect_ok(&format!("WATCH {}", keys.join(" ")))?;
+        if self.watch == Watch::Off {
+            self.watch = Watch::Active;
+        }
+        Ok(())
+    }
+
+    /// UNWATCH stop watching every key watched with [`NubDB::watch`]
+    pub fn unwatch(&mut self) -> Result<(), NubError> {
+        self.expect_ok("UNWATCH")?;
+        self.watch = Watch::Off;
+        Ok(())
+    }
+
+    /// MULTI start a transaction
+    pub fn multi(&mut self) -> Result<Transaction<'_>, NubError> {
+        let response = self.send_command("MULTI")?;
+        if !response.is_ok() {
+            return Err(response.into_unexpected());
+        }
+        Ok(Transaction { client: self, queued: 0, finished: false })
+    }
+}
+
+impl<'a> Transaction<'a> {
+    /// Queue a SET
+    pub fn set(&mut self, key: &str, value: &str, ttl: Option<u32>) -> Result<&mut Self, NubError> {
+        self.client.config.check_key(key)?;
+        self.client.config.check_value(value.len())?;
+        self.queue(&set_command(key, value, ttl, self.client.config.quote_style)?)
+    }
+
+    /// Queue a GET
+    pub fn get(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.client.config.check_key(key)?;
+        self.queue(&format!("GET {}", key))
+    }
+
+    /// Queue a DELETE
+    pub fn del(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.client.config.check_key(key)?;
+        self.queue(&format!("DELETE {}", key))
+    }
+
+    /// Queue an EXISTS
+    pub fn exists(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.client.config.check_key(key)?;
+        self.queue(&format!("EXISTS {}", key))
+    }
+
+    /// Queue an INCR
+    pub fn incr(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.client.config.check_key(key)?;
+        self.queue(&format!("INCR {}", key))
+    }
+
+    /// Queue a DECR
+    pub fn decr(&mut self, key: &str) -> Result<&mut Self, NubError> {
+        self.client.config.check_key(key)?;
+        self.queue(&format!("DECR {}", key))
+    }
+
+    /// Number of queued commands
+    pub fn len(&self) -> usize {
+        self.queued
+    }
+
+    /// Whether no commands are queued
+    pub fn is_empty(&self) -> bool {
+        self.queued == 0
+    }
+
+    fn queue(&mut self, cmd: &str) -> Result<&mut Self, NubError> {
+        // Never auto-reconnect here: a new socket would not be inside MULTI
+        match self.client.audited(cmd, |client| client.try_send_command(cmd))? {
+            Response::Simple(s) if s == "QUEUED" => {
+                self.queued += 1;
+                Ok(self)
+            }
+            other => Err(other.into_unexpected()),
+        }
+    }
+
+    /// EXEC run the queued commands, returning one reply per command in order
+    ///
+    /// Error replies come back as `Response::Error` in their slot, as with
+    /// [`Pipeline::execute`](super::Pipeline::execute). If a watched key was
+    /// written, nothing runs and this fails with
+    /// `NubError::TransactionAborted`; [`Transaction::try_exec`] returns
+    /// `None` instead.
+    pub fn exec(self) -> Result<Vec<Response>, NubError> {
+        self.try_exec()?.ok_or(NubError::TransactionAborted)
+    }
+
+    /// EXEC like [`Transaction::exec`], returning `None` if the transaction
+    /// was aborted because a key watched with [`NubDB::watch`] was written
+    pub fn try_exec(mut self) -> Result<Option<Vec<Response>>, NubError> {
+        self.finished = true;
+        let watch = std::mem::replace(&mut self.client.watch, Watch::Off);
+        if watch == Watch::Lost {
+            // Nothing on this connection guards the keys, so EXEC would
+            // run the commands whatever happened to them
+            let response = self.client.try_send_command("DISCARD")?;
+            if !response.is_ok() {
+                return Err(response.into_unexpected());
+            }
+            return Ok(None);
+        }
+
+        let replies = match self.client.try_send_command("EXEC")? {
+            // A null array: a watched key was written
+            Response::Bulk(None) => return Ok(None),
+            reply => reply.into_array()?,
+        };
+        if replies.len() != self.queued {
+            return Err(NubError::UnexpectedResponse(format!(
+                "EXEC returned {} replies for {} commands", replies.len(), self.queued
+            )));
+        }
+        Ok(Some(replies))
+    }
+
+    /// DISCARD abort the transaction, which also ends any watch
+    pub fn discard(mut self) -> Result<(), NubError> {
+        self.finished = true;
+        self.client.watch = Watch::Off;
+        let response = self.client.try_send_command("DISCARD")?;
+        if !response.is_ok() {
+            return Err(response.into_unexpected());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            // Leave the connection usable; if this fails the socket is
+            // already broken and the next command reports it
+            self.client.watch = Watch::Off;
+            let _ = self.client.try_send_command("DISCARD");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{break_socket, mock_node};
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_multi_exec() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        client.delete("multi:counter").unwrap();
+
+        let mut tx = client.multi().unwrap();
+        tx.set("multi:key", "value", None).unwrap()
+            .incr("multi:counter").unwrap()
+            .get("multi:key").unwrap();
+        assert_eq!(tx.len(), 3);
+
+        let replies = tx.exec().unwrap();
+        assert_eq!(replies, vec![
+            Response::Simple("OK".to_string()),
+            Response::Integer(1),
+            Response::Bulk(Some("value".to_string())),
+        ]);
+
+        client.close().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_multi_discard() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        client.delete("multi:discarded").unwrap();
+
+        let mut tx = client.multi().unwrap();
+        tx.set("multi:discarded", "value", None).unwrap();
+        tx.discard().unwrap();
+        assert_eq!(client.get("multi:discarded").unwrap(), None);
+
+        // Dropping without exec discards too
+        let mut tx = client.multi().unwrap();
+        tx.set("multi:discarded", "value", None).unwrap();
+        drop(tx);
+        assert_eq!(client.get("multi:discarded").unwrap(), None);
+
+        client.close().unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a running server"]
+    fn test_watch_aborts_on_concurrent_write() {
+        let mut client = NubDB::connect("localhost:6379").unwrap();
+        let mut other = NubDB::connect("localhost:6379").unwrap();
+        client.set("watch:balance", "10", None).unwrap();
+
+        client.watch(&["watch:balance"]).unwrap();
+        let balance: i64 = client.get_typed("watch:balance").unwrap().unwrap();
+        other.set("watch:balance", "0", None).unwrap();
+        let mut tx = client.multi().unwrap();
+        tx.set("watch:balance", &(balance - 5).to_string(), None).unwrap();
+        assert_eq!(tx.try_exec().unwrap(), None);
+        assert_eq!(client.get("watch:balance").unwrap().as_deref(), Some("0"));
+
+        // The abort ended the watch, so the same write goes through now
+        client.watch(&["watch:balance"]).unwrap();
+        let mut tx = client.multi().unwrap();
+        tx.set("watch:balance", "5", None).unwrap();
+        assert_eq!(tx.exec().unwrap(), vec![Response::Simple("OK".to_string())]);
+
+        client.watch(&["watch:balance"]).unwrap();
+        other.incr("watch:balance").unwrap();
+        let mut tx = client.multi().unwrap();
+        tx.incr("watch:balance").unwrap();
+        assert!(matches!(tx.exec(), Err(NubError::TransactionAborted)));
+        assert_eq!(client.get("watch:balance").unwrap().as_deref(), Some("6"));
+
+        client.unwatch().unwrap();
+        client.close().unwrap();
+        other.close().unwrap();
+    }
+
+    #[test]
+    fn test_watch_lost_on_reconnect() {
+        let (addr, seen) = mock_node(|cmd| match cmd {
+            c if c.starts_with("SET") => "QUEUED".to_string(),
+            "EXEC" => "*1\nOK".to_string(),
+            _ => "OK".to_string(),
+        });
+        let mut client = NubDB::builder().auto_reconnect(true).connect(&addr).unwrap();
+        client.watch(&["k"]).unwrap();
+        break_socket(&client);
+
+        let mut tx = client.multi().unwrap();
+        tx.set("k", "v", None).unwrap();
+        assert_eq!(tx.try_exec().unwrap(), None);
+
+        // Without a watch the next transaction runs
+        let mut tx = client.multi().unwrap();
+        tx.set("k", "v", None).unwrap();
+        assert!(tx.try_exec().unwrap().is_some());
+        assert_eq!(*seen.lock().unwrap(), vec![
+            "WATCH k", "MULTI", r#"SET k "v""#, "DISCARD", "MULTI", r#"SET k "v""#, "EXEC",
+        ]);
+    }
+}